@@ -1,28 +1,102 @@
+mod pending;
+mod plumtree;
+
 use std::{
-    collections::{HashMap, HashSet, VecDeque},
-    sync::mpsc,
-    time::Instant,
+    collections::{hash_map::DefaultHasher, HashMap, HashSet, VecDeque},
+    hash::{Hash, Hasher},
+    sync::mpsc::{self, RecvTimeoutError},
+    time::{Duration, Instant},
 };
 
 use anyhow::{bail, Result};
 use dist_sys::{Body, Message, Node};
+use pending::PendingAcks;
+use plumtree::Plumtree;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Amount of hash ranges the value set is split into for anti-entropy digests.
+const DIGEST_BUCKETS: usize = 64;
+
+/// Interval in which a digest is send to one of the neighbors.
+const SYNC_INTERVAL: Duration = Duration::from_millis(1000);
+
+/// Interval in which the Plumtree announcements and graft timers are processed.
+const TICK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Environment variable selecting the propagation engine, either `flood` or `plumtree`.
+const ENGINE_VAR: &str = "BROADCAST_ENGINE";
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "snake_case", tag = "type")]
 enum Payload {
+    /// Broadcasts an arbitrary JSON value. Messages are deduplicated by the given id, or by the
+    /// hash of their content when no id is given.
     Broadcast {
-        message: usize,
+        message: Value,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
     },
     BroadcastOk,
-    Read,
+    /// Reads the received values. When a cursor is given, only the values received after that
+    /// cursor are returned.
+    Read {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cursor: Option<usize>,
+    },
+    /// The values in the order they were received, with the cursor to pass to the next read when
+    /// one was requested.
     ReadOk {
-        messages: HashSet<usize>,
+        messages: Vec<Value>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cursor: Option<usize>,
     },
     Topology {
         topology: HashMap<String, HashSet<String>>,
     },
     TopologyOk,
+    /// Digest of the value set of the sender, one hash per bucket.
+    Sync {
+        digest: Vec<u64>,
+    },
+    /// The buckets which differ from the send digest, with the values the
+    /// responding node holds in those buckets.
+    SyncOk {
+        buckets: Vec<usize>,
+        messages: HashMap<String, Value>,
+    },
+    /// The values the receiving node was found to be missing.
+    SyncDiff {
+        messages: HashMap<String, Value>,
+    },
+    Stats,
+    /// Amount of retried broadcasts and broadcasts still awaiting an acknowledgement.
+    StatsOk {
+        retries: usize,
+        pending: usize,
+    },
+    /// Eager push of a message along the broadcast tree.
+    Gossip {
+        id: String,
+        message: Value,
+    },
+    /// Lazy announcement of messages received by the sender.
+    IHave {
+        messages: Vec<String>,
+    },
+    /// Requests the missing messages, and adds the link back into the broadcast tree.
+    Graft {
+        messages: Vec<String>,
+    },
+    /// Removes the link from the broadcast tree.
+    Prune,
+}
+
+#[derive(Debug)]
+enum Engine {
+    /// Forward every new message to all neighbors, retrying until acknowledged.
+    Flood,
+    Plumtree(Plumtree),
 }
 
 #[derive(Debug)]
@@ -31,8 +105,15 @@ struct BroadcastNode {
     rx: mpsc::Receiver<Message<Payload>>,
     node_id: String,
     msg_id: usize,
-    values: HashSet<usize>,
+    values: HashMap<String, Value>,
+    log: Vec<String>,
+    digest: Vec<u64>,
     neighbors: HashSet<String>,
+    pending: PendingAcks,
+    engine: Engine,
+    last_sync: Instant,
+    last_tick: Instant,
+    sync_round: usize,
 }
 
 impl Node<Payload> for BroadcastNode {
@@ -40,43 +121,73 @@ impl Node<Payload> for BroadcastNode {
         tx: mpsc::Sender<Message<Payload>>,
         rx: mpsc::Receiver<Message<Payload>>,
         node_id: String,
-        _other: Vec<String>,
+        other: Vec<String>,
     ) -> Self {
+        let (engine, neighbors) = match std::env::var(ENGINE_VAR).as_deref() {
+            Ok("plumtree") => (
+                Engine::Plumtree(Plumtree::new(&node_id, &other)),
+                other.into_iter().filter(|id| id != &node_id).collect(),
+            ),
+            _ => (Engine::Flood, HashSet::new()),
+        };
+
         Self {
             tx,
             rx,
             node_id,
             msg_id: 0,
-            values: HashSet::with_capacity(512),
-            neighbors: HashSet::new(),
+            values: HashMap::with_capacity(512),
+            log: Vec::with_capacity(512),
+            digest: vec![0; DIGEST_BUCKETS],
+            neighbors,
+            pending: PendingAcks::default(),
+            engine,
+            last_sync: Instant::now(),
+            last_tick: Instant::now(),
+            sync_round: 0,
         }
     }
 
     fn run(&mut self) -> Result<()> {
         let mut queue = VecDeque::<Message<Payload>>::with_capacity(16);
-        let mut unfinished = VecDeque::<(Instant, Message<Payload>)>::with_capacity(16);
 
         loop {
-            while let Some((time, msg)) = unfinished.pop_front() {
-                if time.elapsed().as_millis() < 300 {
-                    unfinished.push_front((time, msg));
-                    break;
-                }
+            if self.last_sync.elapsed() >= SYNC_INTERVAL {
+                self.send_digest()?;
+            }
 
-                self.tx.send(msg.clone())?;
-                unfinished.push_back((Instant::now(), msg));
+            for msg in self.pending.due() {
+                self.tx.send(msg)?;
+            }
+
+            if self.last_tick.elapsed() >= TICK_INTERVAL {
+                self.last_tick = Instant::now();
+
+                if let Engine::Plumtree(ref mut plumtree) = self.engine {
+                    let outgoing = plumtree.tick(self.last_tick);
+                    self.send_all(outgoing)?;
+                }
             }
 
             if let Some(msg) = queue.pop_front() {
                 match msg.body.payload {
-                    Payload::Broadcast { message } => {
+                    Payload::Broadcast {
+                        ref message,
+                        ref id,
+                    } => {
                         self.send_response(&msg, Payload::BroadcastOk)?;
 
-                        if self.values.contains(&message) {
+                        let id = id.clone().unwrap_or_else(|| message_id(message));
+
+                        if !self.insert_value(id.clone(), message.clone()) {
                             continue;
                         }
 
-                        self.values.insert(message);
+                        if let Engine::Plumtree(ref mut plumtree) = self.engine {
+                            let outgoing = plumtree.broadcast(&id, message, None);
+                            self.send_all(outgoing)?;
+                            continue;
+                        }
 
                         for neighbor in self.neighbors.clone() {
                             if neighbor == msg.src {
@@ -84,43 +195,153 @@ impl Node<Payload> for BroadcastNode {
                             }
 
                             let msg = self.generate_message(
-                                Payload::Broadcast { message },
+                                Payload::Broadcast {
+                                    message: message.clone(),
+                                    id: Some(id.clone()),
+                                },
                                 neighbor.to_string(),
                                 None,
                             );
 
-                            self.tx.send(msg.clone())?;
-                            unfinished.push_back((Instant::now(), msg))
+                            if let Some(msg) = self.pending.push(msg) {
+                                self.tx.send(msg)?;
+                            }
                         }
                     }
-                    Payload::Read => {
+                    Payload::Read { cursor } => {
+                        let start = cursor.unwrap_or(0).min(self.log.len());
+
                         self.send_response(
                             &msg,
                             Payload::ReadOk {
-                                messages: self.values.clone(),
+                                messages: self.log[start..]
+                                    .iter()
+                                    .map(|id| self.values[id].clone())
+                                    .collect(),
+                                cursor: cursor.map(|_| self.log.len()),
                             },
                         )?;
                     }
                     Payload::Topology { ref topology } => {
                         self.neighbors = topology[&self.node_id].clone();
+
+                        if let Engine::Plumtree(ref mut plumtree) = self.engine {
+                            plumtree.set_neighbors(&self.neighbors);
+                        }
+
                         self.send_response(&msg, Payload::TopologyOk)?;
                     }
                     Payload::BroadcastOk => {
-                        let msg_id = msg.body.in_reply_to;
-                        let index = unfinished
+                        let Some(msg_id) = msg.body.in_reply_to else {
+                            continue;
+                        };
+
+                        if let Some(next) = self.pending.ack(&msg.src, msg_id) {
+                            self.tx.send(next)?;
+                        }
+                    }
+                    Payload::Stats => {
+                        self.send_response(
+                            &msg,
+                            Payload::StatsOk {
+                                retries: self.pending.retries(),
+                                pending: self.pending.len(),
+                            },
+                        )?;
+                    }
+                    Payload::Sync { ref digest } => {
+                        let buckets = self
+                            .digest
                             .iter()
-                            .position(|(_, msg)| msg_id == msg.body.msg_id);
+                            .zip(digest)
+                            .enumerate()
+                            .filter(|(_, (own, other))| own != other)
+                            .map(|(bucket, _)| bucket)
+                            .collect::<Vec<_>>();
+
+                        if buckets.is_empty() {
+                            continue;
+                        }
 
-                        if let Some(index) = index {
-                            unfinished.remove(index);
+                        let messages = self.values_in_buckets(&buckets);
+                        self.send_response(&msg, Payload::SyncOk { buckets, messages })?;
+                    }
+                    Payload::SyncOk {
+                        ref buckets,
+                        ref messages,
+                    } => {
+                        let missing = self
+                            .values_in_buckets(buckets)
+                            .into_iter()
+                            .filter(|(id, _)| !messages.contains_key(id))
+                            .collect::<HashMap<_, _>>();
+
+                        for (id, message) in messages {
+                            self.insert_value(id.clone(), message.clone());
+                        }
+
+                        if !missing.is_empty() {
+                            self.send_response(&msg, Payload::SyncDiff { messages: missing })?;
+                        }
+                    }
+                    Payload::SyncDiff { messages } => {
+                        for (id, message) in messages {
+                            self.insert_value(id, message);
+                        }
+                    }
+                    Payload::Gossip {
+                        ref id,
+                        ref message,
+                    } => {
+                        let new = self.insert_value(id.clone(), message.clone());
+                        let Some(plumtree) = self.plumtree() else {
+                            continue;
+                        };
+
+                        let outgoing = if new {
+                            plumtree.broadcast(id, message, Some(&msg.src))
+                        } else {
+                            plumtree.duplicate(&msg.src)
+                        };
+                        self.send_all(outgoing)?;
+                    }
+                    Payload::IHave { messages } => {
+                        let missing = messages
+                            .into_iter()
+                            .filter(|id| !self.values.contains_key(id))
+                            .collect::<Vec<_>>();
+
+                        if let Some(plumtree) = self.plumtree() {
+                            plumtree.ihave(&msg.src, missing);
+                        }
+                    }
+                    Payload::Graft { messages } => {
+                        if let Some(plumtree) = self.plumtree() {
+                            plumtree.graft(&msg.src);
+                        }
+
+                        let outgoing = messages
+                            .into_iter()
+                            .filter_map(|id| {
+                                let message = self.values.get(&id)?.clone();
+                                Some((msg.src.clone(), Payload::Gossip { id, message }))
+                            })
+                            .collect();
+                        self.send_all(outgoing)?;
+                    }
+                    Payload::Prune => {
+                        if let Some(plumtree) = self.plumtree() {
+                            plumtree.prune(&msg.src);
                         }
                     }
                     m => bail!("Message invalid for node: {m:?}"),
                 }
-            } else if let Ok(msg) = self.rx.recv() {
-                queue.push_back(msg);
             } else {
-                break;
+                match self.rx.recv_timeout(Duration::from_millis(50)) {
+                    Ok(msg) => queue.push_back(msg),
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
             }
         }
 
@@ -158,9 +379,340 @@ impl BroadcastNode {
 
         Ok(())
     }
+
+    fn send_all(&mut self, outgoing: Vec<(String, Payload)>) -> Result<()> {
+        for (dest, payload) in outgoing {
+            let msg = self.generate_message(payload, dest, None);
+            self.tx.send(msg)?;
+        }
+
+        Ok(())
+    }
+
+    /// The broadcast tree, `None` while flooding. Tree messages from peers running Plumtree are
+    /// then ignored, apart from the values they carry.
+    fn plumtree(&mut self) -> Option<&mut Plumtree> {
+        match self.engine {
+            Engine::Plumtree(ref mut plumtree) => Some(plumtree),
+            Engine::Flood => None,
+        }
+    }
+
+    /// Adds a value to the set, keeping the log and digest up to date. Returns `false` when the
+    /// value was already known.
+    fn insert_value(&mut self, id: String, value: Value) -> bool {
+        if self.values.contains_key(&id) {
+            return false;
+        }
+
+        let hash = hash(&id);
+        let bucket = hash as usize % DIGEST_BUCKETS;
+        self.digest[bucket] = self.digest[bucket].wrapping_add(hash);
+
+        if let Some(plumtree) = self.plumtree() {
+            plumtree.received(&id);
+        }
+
+        self.log.push(id.clone());
+        self.values.insert(id, value);
+
+        true
+    }
+
+    fn values_in_buckets(&self, buckets: &[usize]) -> HashMap<String, Value> {
+        self.values
+            .iter()
+            .filter(|(id, _)| buckets.contains(&(hash(id) as usize % DIGEST_BUCKETS)))
+            .map(|(id, value)| (id.clone(), value.clone()))
+            .collect()
+    }
+
+    /// Sends the digest of the value set to the next neighbor in line, which replies with the
+    /// buckets that differ.
+    fn send_digest(&mut self) -> Result<()> {
+        self.last_sync = Instant::now();
+
+        if self.neighbors.is_empty() {
+            return Ok(());
+        }
+
+        let mut neighbors = self.neighbors.iter().collect::<Vec<_>>();
+        neighbors.sort();
+        let neighbor = neighbors[self.sync_round % neighbors.len()].clone();
+        self.sync_round += 1;
+
+        let msg = self.generate_message(
+            Payload::Sync {
+                digest: self.digest.clone(),
+            },
+            neighbor,
+            None,
+        );
+        self.tx.send(msg)?;
+
+        Ok(())
+    }
+}
+
+fn hash<T: Hash + ?Sized>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Identifies a message by the hash of its content. Object keys are serialized in sorted order,
+/// so equal values get equal ids on every node.
+fn message_id(message: &Value) -> String {
+    format!("{:016x}", hash(&message.to_string()))
 }
 
 fn main() -> Result<()> {
     dist_sys::run_dist_sys::<BroadcastNode, Payload>()?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    fn client_message(src: &str, msg_id: usize, payload: Payload) -> Message<Payload> {
+        Message {
+            src: src.to_string(),
+            dest: "n1".to_string(),
+            body: Body {
+                msg_id: Some(msg_id),
+                in_reply_to: None,
+                payload,
+            },
+        }
+    }
+
+    fn broadcast(message: Value) -> Payload {
+        Payload::Broadcast { message, id: None }
+    }
+
+    fn recv_until<F>(rx: &mpsc::Receiver<Message<Payload>>, predicate: F) -> Message<Payload>
+    where
+        F: Fn(&Message<Payload>) -> bool,
+    {
+        loop {
+            let msg = rx
+                .recv_timeout(Duration::from_millis(2000))
+                .expect("Failed to get a response in a reasonable time");
+
+            if predicate(&msg) {
+                return msg;
+            }
+        }
+    }
+
+    #[test]
+    fn sync_transfers_missing_values() {
+        let (in_tx, in_rx) = mpsc::channel::<Message<Payload>>();
+        let (out_tx, out_rx) = mpsc::channel::<Message<Payload>>();
+
+        thread::spawn(move || {
+            let mut node = BroadcastNode::initialize(out_tx, in_rx, "n1".to_string(), vec![]);
+            node.run().unwrap();
+        });
+
+        in_tx
+            .send(client_message(
+                "c1",
+                1,
+                Payload::Topology {
+                    topology: HashMap::from([(
+                        "n1".to_string(),
+                        HashSet::from(["n2".to_string()]),
+                    )]),
+                },
+            ))
+            .unwrap();
+        in_tx
+            .send(client_message("c1", 2, broadcast(7.into())))
+            .unwrap();
+        in_tx
+            .send(client_message(
+                "n2",
+                3,
+                Payload::Sync {
+                    digest: vec![0; DIGEST_BUCKETS],
+                },
+            ))
+            .unwrap();
+
+        let response = recv_until(&out_rx, |msg| msg.body.in_reply_to == Some(3));
+        match response.body.payload {
+            Payload::SyncOk { messages, .. } => {
+                assert_eq!(
+                    messages.into_values().collect::<Vec<_>>(),
+                    vec![Value::from(7)]
+                )
+            }
+            payload => panic!("Unexpected response: {payload:?}"),
+        }
+
+        in_tx
+            .send(client_message(
+                "n2",
+                4,
+                Payload::SyncDiff {
+                    messages: HashMap::from([(message_id(&9.into()), 9.into())]),
+                },
+            ))
+            .unwrap();
+        in_tx
+            .send(client_message("c1", 5, Payload::Read { cursor: None }))
+            .unwrap();
+
+        let response = recv_until(&out_rx, |msg| msg.body.in_reply_to == Some(5));
+        match response.body.payload {
+            Payload::ReadOk { messages, cursor } => {
+                assert_eq!(messages, vec![Value::from(7), Value::from(9)]);
+                assert_eq!(cursor, None);
+            }
+            payload => panic!("Unexpected response: {payload:?}"),
+        }
+    }
+
+    #[test]
+    fn flooding_node_ignores_tree_messages() {
+        let (in_tx, in_rx) = mpsc::channel::<Message<Payload>>();
+        let (out_tx, out_rx) = mpsc::channel::<Message<Payload>>();
+
+        thread::spawn(move || {
+            let mut node = BroadcastNode::initialize(out_tx, in_rx, "n1".to_string(), vec![]);
+            node.run().unwrap();
+        });
+
+        in_tx.send(client_message("n2", 1, Payload::Prune)).unwrap();
+        in_tx
+            .send(client_message(
+                "n2",
+                2,
+                Payload::IHave {
+                    messages: vec!["9".to_string()],
+                },
+            ))
+            .unwrap();
+        in_tx
+            .send(client_message(
+                "n2",
+                3,
+                Payload::Gossip {
+                    id: "5".to_string(),
+                    message: 5.into(),
+                },
+            ))
+            .unwrap();
+        in_tx
+            .send(client_message("c1", 4, Payload::Read { cursor: None }))
+            .unwrap();
+
+        let response = recv_until(&out_rx, |msg| msg.body.in_reply_to == Some(4));
+        match response.body.payload {
+            Payload::ReadOk { messages, .. } => assert_eq!(messages, vec![Value::from(5)]),
+            payload => panic!("Unexpected response: {payload:?}"),
+        }
+    }
+
+    #[test]
+    fn read_with_cursor_returns_new_values() {
+        let (in_tx, in_rx) = mpsc::channel::<Message<Payload>>();
+        let (out_tx, out_rx) = mpsc::channel::<Message<Payload>>();
+
+        thread::spawn(move || {
+            let mut node = BroadcastNode::initialize(out_tx, in_rx, "n1".to_string(), vec![]);
+            node.run().unwrap();
+        });
+
+        for (msg_id, message) in [(1, 3), (2, 1), (3, 3)] {
+            in_tx
+                .send(client_message("c1", msg_id, broadcast(message.into())))
+                .unwrap();
+        }
+        in_tx
+            .send(client_message("c1", 4, Payload::Read { cursor: Some(0) }))
+            .unwrap();
+
+        let response = recv_until(&out_rx, |msg| msg.body.in_reply_to == Some(4));
+        match response.body.payload {
+            Payload::ReadOk { messages, cursor } => {
+                assert_eq!(messages, vec![Value::from(3), Value::from(1)]);
+                assert_eq!(cursor, Some(2));
+            }
+            payload => panic!("Unexpected response: {payload:?}"),
+        }
+
+        in_tx
+            .send(client_message("c1", 5, broadcast(8.into())))
+            .unwrap();
+        in_tx
+            .send(client_message("c1", 6, Payload::Read { cursor: Some(2) }))
+            .unwrap();
+
+        let response = recv_until(&out_rx, |msg| msg.body.in_reply_to == Some(6));
+        match response.body.payload {
+            Payload::ReadOk { messages, cursor } => {
+                assert_eq!(messages, vec![Value::from(8)]);
+                assert_eq!(cursor, Some(3));
+            }
+            payload => panic!("Unexpected response: {payload:?}"),
+        }
+    }
+
+    #[test]
+    fn deduplicates_json_messages() {
+        let (in_tx, in_rx) = mpsc::channel::<Message<Payload>>();
+        let (out_tx, out_rx) = mpsc::channel::<Message<Payload>>();
+
+        thread::spawn(move || {
+            let mut node = BroadcastNode::initialize(out_tx, in_rx, "n1".to_string(), vec![]);
+            node.run().unwrap();
+        });
+
+        let payloads = [
+            r#"{"type":"broadcast","message":{"flag":"a","enabled":true}}"#,
+            r#"{"type":"broadcast","message":{"enabled":true,"flag":"a"}}"#,
+            r#"{"type":"broadcast","message":"v1","id":"config"}"#,
+            r#"{"type":"broadcast","message":"v2","id":"config"}"#,
+        ];
+
+        for (msg_id, payload) in payloads.into_iter().enumerate() {
+            in_tx
+                .send(client_message(
+                    "c1",
+                    msg_id,
+                    serde_json::from_str(payload).unwrap(),
+                ))
+                .unwrap();
+        }
+        in_tx
+            .send(client_message("c1", 10, Payload::Read { cursor: None }))
+            .unwrap();
+
+        let response = recv_until(&out_rx, |msg| msg.body.in_reply_to == Some(10));
+        match response.body.payload {
+            Payload::ReadOk { messages, .. } => assert_eq!(
+                messages,
+                vec![
+                    serde_json::json!({ "flag": "a", "enabled": true }),
+                    Value::from("v1")
+                ]
+            ),
+            payload => panic!("Unexpected response: {payload:?}"),
+        }
+    }
+
+    #[test]
+    fn read_without_cursor_deserializes() {
+        let msg = serde_json::from_str::<Message<Payload>>(
+            r#"{"src":"c1","dest":"n1","body":{"type":"read","msg_id":1}}"#,
+        )
+        .unwrap();
+
+        assert!(matches!(msg.body.payload, Payload::Read { cursor: None }));
+    }
+}
@@ -0,0 +1,221 @@
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use dist_sys::Message;
+use rand::Rng;
+
+use crate::Payload;
+
+/// Delay before the first retry of a message.
+const BASE_BACKOFF: Duration = Duration::from_millis(300);
+
+/// Upper bound for the delay between retries to a destination.
+const MAX_BACKOFF: Duration = Duration::from_millis(5000);
+
+/// Maximum amount of unacknowledged messages per destination, further messages are queued.
+const MAX_IN_FLIGHT: usize = 32;
+
+#[derive(Debug)]
+struct Pending {
+    deadline: Instant,
+    msg: Message<Payload>,
+}
+
+#[derive(Debug, Default)]
+struct Destination {
+    in_flight: usize,
+    failures: u32,
+    queued: VecDeque<Message<Payload>>,
+}
+
+/// Messages waiting for an acknowledgement, indexed by their `msg_id`.
+#[derive(Debug, Default)]
+pub struct PendingAcks {
+    pending: HashMap<usize, Pending>,
+    deadlines: BinaryHeap<Reverse<(Instant, usize)>>,
+    destinations: HashMap<String, Destination>,
+    retries: usize,
+}
+
+impl PendingAcks {
+    /// Tracks a message which should be acknowledged. Returns the message when it can be send
+    /// right away, or `None` when the destination has too many messages in flight.
+    pub fn push(&mut self, msg: Message<Payload>) -> Option<Message<Payload>> {
+        let destination = self.destinations.entry(msg.dest.clone()).or_default();
+
+        if destination.in_flight >= MAX_IN_FLIGHT {
+            destination.queued.push_back(msg);
+            return None;
+        }
+
+        destination.in_flight += 1;
+        let deadline = Instant::now() + backoff(destination.failures);
+        self.track(deadline, msg.clone());
+
+        Some(msg)
+    }
+
+    /// Marks the message with the given id as acknowledged by `src`. Returns the next queued
+    /// message for that destination if one can now be send.
+    pub fn ack(&mut self, src: &str, msg_id: usize) -> Option<Message<Payload>> {
+        match self.pending.get(&msg_id) {
+            Some(pending) if pending.msg.dest == src => {}
+            _ => return None,
+        }
+
+        self.pending.remove(&msg_id);
+
+        let destination = self.destinations.get_mut(src)?;
+        destination.in_flight -= 1;
+        destination.failures = 0;
+
+        let msg = destination.queued.pop_front()?;
+        destination.in_flight += 1;
+        let deadline = Instant::now() + backoff(0);
+        self.track(deadline, msg.clone());
+
+        Some(msg)
+    }
+
+    /// Returns all messages whose retry deadline has passed, rescheduling them with an increased
+    /// backoff for their destination.
+    pub fn due(&mut self) -> Vec<Message<Payload>> {
+        let now = Instant::now();
+        let mut due = vec![];
+
+        while let Some(Reverse((deadline, msg_id))) = self.deadlines.peek().copied() {
+            if deadline > now {
+                break;
+            }
+
+            self.deadlines.pop();
+
+            match self.pending.get(&msg_id) {
+                Some(pending) if pending.deadline == deadline => due.push(msg_id),
+                _ => continue,
+            }
+        }
+
+        let mut failed = due
+            .iter()
+            .map(|msg_id| self.pending[msg_id].msg.dest.clone())
+            .collect::<Vec<_>>();
+        failed.sort();
+        failed.dedup();
+
+        for dest in failed {
+            if let Some(destination) = self.destinations.get_mut(&dest) {
+                destination.failures = destination.failures.saturating_add(1);
+            }
+        }
+
+        due.into_iter()
+            .map(|msg_id| {
+                let pending = self
+                    .pending
+                    .remove(&msg_id)
+                    .expect("Due message is pending");
+                let failures = self.destinations[&pending.msg.dest].failures;
+
+                self.retries += 1;
+                self.track(now + backoff(failures), pending.msg.clone());
+
+                pending.msg
+            })
+            .collect()
+    }
+
+    /// Total amount of retries send.
+    pub fn retries(&self) -> usize {
+        self.retries
+    }
+
+    /// Amount of messages waiting for an acknowledgement, including queued messages.
+    pub fn len(&self) -> usize {
+        self.pending.len()
+            + self
+                .destinations
+                .values()
+                .map(|destination| destination.queued.len())
+                .sum::<usize>()
+    }
+
+    fn track(&mut self, deadline: Instant, msg: Message<Payload>) {
+        let msg_id = msg.body.msg_id.expect("Tracked messages have an id");
+
+        self.deadlines.push(Reverse((deadline, msg_id)));
+        self.pending.insert(msg_id, Pending { deadline, msg });
+    }
+}
+
+/// Exponential backoff for the given amount of failures, with up to 50% jitter.
+fn backoff(failures: u32) -> Duration {
+    let delay = BASE_BACKOFF
+        .saturating_mul(2u32.saturating_pow(failures.min(16)))
+        .min(MAX_BACKOFF);
+    let jitter = rand::thread_rng().gen_range(0..=delay.as_millis() as u64 / 2);
+
+    delay + Duration::from_millis(jitter)
+}
+
+#[cfg(test)]
+mod tests {
+    use dist_sys::Body;
+
+    use super::*;
+
+    fn broadcast(msg_id: usize, dest: &str) -> Message<Payload> {
+        Message {
+            src: "n1".to_string(),
+            dest: dest.to_string(),
+            body: Body {
+                msg_id: Some(msg_id),
+                in_reply_to: None,
+                payload: Payload::Broadcast {
+                    message: msg_id.into(),
+                    id: None,
+                },
+            },
+        }
+    }
+
+    #[test]
+    fn caps_in_flight_per_destination() {
+        let mut pending = PendingAcks::default();
+
+        for msg_id in 0..MAX_IN_FLIGHT {
+            assert!(pending.push(broadcast(msg_id, "n2")).is_some());
+        }
+
+        assert!(pending.push(broadcast(MAX_IN_FLIGHT, "n2")).is_none());
+        assert!(pending.push(broadcast(MAX_IN_FLIGHT + 1, "n3")).is_some());
+        assert_eq!(pending.len(), MAX_IN_FLIGHT + 2);
+
+        let next = pending.ack("n2", 0).expect("Queued message is released");
+        assert_eq!(next.body.msg_id, Some(MAX_IN_FLIGHT));
+        assert_eq!(pending.len(), MAX_IN_FLIGHT + 1);
+    }
+
+    #[test]
+    fn ack_from_other_source_is_ignored() {
+        let mut pending = PendingAcks::default();
+        pending.push(broadcast(0, "n2"));
+
+        pending.ack("n3", 0);
+        assert_eq!(pending.len(), 1);
+
+        pending.ack("n2", 0);
+        assert_eq!(pending.len(), 0);
+    }
+
+    #[test]
+    fn backoff_grows_and_is_capped() {
+        assert!(backoff(0) >= BASE_BACKOFF);
+        assert!(backoff(0) <= BASE_BACKOFF + BASE_BACKOFF / 2);
+        assert!(backoff(2) >= BASE_BACKOFF * 4);
+        assert!(backoff(u32::MAX) <= MAX_BACKOFF + MAX_BACKOFF / 2);
+    }
+}
@@ -0,0 +1,244 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    time::{Duration, Instant},
+};
+
+use serde_json::Value;
+
+use crate::Payload;
+
+/// Time to wait for a message announced with `IHave` before grafting the announcer.
+const IHAVE_TIMEOUT: Duration = Duration::from_millis(400);
+
+/// Time to wait for a grafted peer to deliver a missing message before trying the next announcer.
+const GRAFT_TIMEOUT: Duration = Duration::from_millis(200);
+
+#[derive(Debug)]
+struct Missing {
+    deadline: Instant,
+    announcers: VecDeque<String>,
+}
+
+/// Epidemic broadcast tree, messages are pushed eagerly along a spanning tree and announced lazily
+/// to all other peers. Redundant tree links are pruned, and links are grafted back into the tree
+/// when an announced message does not arrive in time.
+#[derive(Debug)]
+pub struct Plumtree {
+    eager: HashSet<String>,
+    lazy: HashSet<String>,
+    announcements: HashMap<String, Vec<String>>,
+    missing: HashMap<String, Missing>,
+}
+
+impl Plumtree {
+    pub fn new(node_id: &str, nodes: &[String]) -> Self {
+        Self {
+            eager: nodes.iter().filter(|id| *id != node_id).cloned().collect(),
+            lazy: HashSet::new(),
+            announcements: HashMap::new(),
+            missing: HashMap::new(),
+        }
+    }
+
+    /// Seeds the tree with the given neighbors, all other peers are only announced to.
+    pub fn set_neighbors(&mut self, neighbors: &HashSet<String>) {
+        let peers = self
+            .eager
+            .drain()
+            .chain(self.lazy.drain())
+            .collect::<Vec<_>>();
+
+        for peer in peers {
+            if neighbors.contains(&peer) {
+                self.eager.insert(peer);
+            } else {
+                self.lazy.insert(peer);
+            }
+        }
+    }
+
+    /// Propagates a message which was not seen before, `from` being the peer which delivered it.
+    pub fn broadcast(
+        &mut self,
+        id: &str,
+        message: &Value,
+        from: Option<&str>,
+    ) -> Vec<(String, Payload)> {
+        self.received(id);
+
+        if let Some(from) = from {
+            self.lazy.remove(from);
+            self.eager.insert(from.to_string());
+        }
+
+        for peer in &self.lazy {
+            if Some(peer.as_str()) != from {
+                self.announcements
+                    .entry(peer.clone())
+                    .or_default()
+                    .push(id.to_string());
+            }
+        }
+
+        self.eager
+            .iter()
+            .filter(|peer| Some(peer.as_str()) != from)
+            .map(|peer| {
+                let payload = Payload::Gossip {
+                    id: id.to_string(),
+                    message: message.clone(),
+                };
+
+                (peer.clone(), payload)
+            })
+            .collect()
+    }
+
+    /// Handles a message which was already received, the link to the sender is redundant.
+    pub fn duplicate(&mut self, from: &str) -> Vec<(String, Payload)> {
+        if !self.eager.remove(from) {
+            return vec![];
+        }
+
+        self.lazy.insert(from.to_string());
+        vec![(from.to_string(), Payload::Prune)]
+    }
+
+    /// Stops waiting for an announced message, which arrived outside the tree, for example through
+    /// anti-entropy.
+    pub fn received(&mut self, id: &str) {
+        self.missing.remove(id);
+    }
+
+    /// Registers announced messages which are not yet received.
+    pub fn ihave(&mut self, from: &str, missing: impl IntoIterator<Item = String>) {
+        for id in missing {
+            self.missing
+                .entry(id)
+                .or_insert_with(|| Missing {
+                    deadline: Instant::now() + IHAVE_TIMEOUT,
+                    announcers: VecDeque::new(),
+                })
+                .announcers
+                .push_back(from.to_string());
+        }
+    }
+
+    /// Adds the peer back into the tree.
+    pub fn graft(&mut self, from: &str) {
+        self.lazy.remove(from);
+        self.eager.insert(from.to_string());
+    }
+
+    /// Removes the peer from the tree, it will only receive announcements.
+    pub fn prune(&mut self, from: &str) {
+        if self.eager.remove(from) {
+            self.lazy.insert(from.to_string());
+        }
+    }
+
+    /// Flushes the queued announcements, and grafts announcers of messages which did not arrive
+    /// in time.
+    pub fn tick(&mut self, now: Instant) -> Vec<(String, Payload)> {
+        let mut outgoing = self
+            .announcements
+            .drain()
+            .map(|(peer, messages)| (peer, Payload::IHave { messages }))
+            .collect::<Vec<_>>();
+
+        let mut grafts = HashMap::<String, Vec<String>>::new();
+
+        self.missing.retain(|id, missing| {
+            if missing.deadline > now {
+                return true;
+            }
+
+            let Some(announcer) = missing.announcers.pop_front() else {
+                return false;
+            };
+
+            grafts.entry(announcer).or_default().push(id.clone());
+            missing.deadline = now + GRAFT_TIMEOUT;
+
+            true
+        });
+
+        for (peer, messages) in grafts {
+            self.graft(&peer);
+            outgoing.push((peer, Payload::Graft { messages }));
+        }
+
+        outgoing
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plumtree() -> Plumtree {
+        let nodes = ["n1", "n2", "n3", "n4"].map(String::from);
+        let mut plumtree = Plumtree::new("n1", &nodes);
+        plumtree.set_neighbors(&HashSet::from(["n2".to_string(), "n3".to_string()]));
+
+        plumtree
+    }
+
+    #[test]
+    fn pushes_eagerly_and_announces_lazily() {
+        let mut plumtree = plumtree();
+
+        let mut pushed = plumtree
+            .broadcast("5", &Value::from(5), Some("n2"))
+            .into_iter()
+            .map(|(peer, _)| peer)
+            .collect::<Vec<_>>();
+        pushed.sort();
+        assert_eq!(pushed, vec!["n3".to_string()]);
+
+        let announced = plumtree.tick(Instant::now());
+        assert!(matches!(
+            announced.as_slice(),
+            [(peer, Payload::IHave { messages })] if peer == "n4" && messages == &vec!["5".to_string()]
+        ));
+    }
+
+    #[test]
+    fn duplicate_prunes_sender() {
+        let mut plumtree = plumtree();
+
+        let outgoing = plumtree.duplicate("n3");
+        assert!(matches!(outgoing.as_slice(), [(peer, Payload::Prune)] if peer == "n3"));
+        assert!(plumtree.lazy.contains("n3"));
+
+        assert!(plumtree.duplicate("n3").is_empty());
+    }
+
+    #[test]
+    fn missing_message_grafts_announcer() {
+        let mut plumtree = plumtree();
+        plumtree.ihave("n4", ["9".to_string()]);
+
+        assert!(plumtree.tick(Instant::now()).is_empty());
+
+        let outgoing = plumtree.tick(Instant::now() + IHAVE_TIMEOUT);
+        assert!(matches!(
+            outgoing.as_slice(),
+            [(peer, Payload::Graft { messages })] if peer == "n4" && messages == &vec!["9".to_string()]
+        ));
+        assert!(plumtree.eager.contains("n4"));
+
+        plumtree.broadcast("9", &Value::from(9), Some("n4"));
+        assert!(plumtree.missing.is_empty());
+    }
+
+    #[test]
+    fn received_message_is_not_grafted() {
+        let mut plumtree = plumtree();
+        plumtree.ihave("n4", ["9".to_string()]);
+        plumtree.received("9");
+
+        assert!(plumtree.tick(Instant::now() + IHAVE_TIMEOUT).is_empty());
+        assert!(!plumtree.eager.contains("n4"));
+    }
+}
@@ -1,132 +1,517 @@
 use std::{
-    collections::{HashMap, VecDeque},
-    sync::mpsc,
-    time::Duration,
+    collections::HashMap,
+    sync::mpsc::{self, RecvTimeoutError},
+    time::{Duration, Instant},
 };
 
 use anyhow::{bail, Result};
-use dist_sys::{Body, Message, Node};
+use dist_sys::{
+    crdt::PnCounter,
+    gossip::{CrdtNode, CrdtPayload, Workload},
+    Body, Message, Node,
+};
 use serde::{Deserialize, Serialize};
 
+/// Environment variable selecting how the counter is kept, either `gossip` or `seq-kv`.
+const MODE_VAR: &str = "COUNTER_MODE";
+
+const SEQ_KV: &str = "seq-kv";
+
+/// Key in `seq-kv` holding the counter.
+const COUNTER_KEY: &str = "counter";
+
+/// `seq-kv` error code for reads of a key which does not exist.
+const KEY_DOES_NOT_EXIST: usize = 20;
+
+/// `seq-kv` error code for a compare-and-set with a stale `from` value.
+const PRECONDITION_FAILED: usize = 22;
+
+/// Maelstrom error code for a request which may or may not have taken effect.
+const TIMEOUT: usize = 0;
+
+/// Time to wait for a reply of `seq-kv` before the request is retried, or given up on when it may
+/// have been applied.
+const KV_TIMEOUT: Duration = Duration::from_secs(1);
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "snake_case", tag = "type")]
 enum Payload {
-    Add { delta: usize },
+    Add {
+        delta: i64,
+    },
     AddOk,
-    Read,
-    ReadOk { value: usize },
-    Replicate { value: usize },
+    /// Read of the counter by a client, or of a key when send to `seq-kv`.
+    Read {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key: Option<String>,
+    },
+    ReadOk {
+        value: i64,
+    },
+    Write {
+        key: String,
+        value: i64,
+    },
+    WriteOk,
+    Cas {
+        key: String,
+        from: i64,
+        to: i64,
+        create_if_not_exists: bool,
+    },
+    CasOk,
+    Error {
+        code: usize,
+        text: String,
+    },
 }
 
+/// Grow-only counter workload, also accepting negative deltas for Maelstrom's `pn-counter`.
+struct CounterWorkload;
+
+impl Workload for CounterWorkload {
+    type State = PnCounter;
+    type Payload = Payload;
+
+    fn handle(counter: &mut PnCounter, node_id: &str, request: Payload) -> Result<(Payload, bool)> {
+        match request {
+            Payload::Add { delta } => {
+                counter.add(node_id, delta);
+                Ok((Payload::AddOk, delta != 0))
+            }
+            Payload::Read { .. } => Ok((
+                Payload::ReadOk {
+                    value: counter.value(),
+                },
+                false,
+            )),
+            m => bail!("Invalid message for client: {m:?}"),
+        }
+    }
+}
+
+/// Step a client request is in while waiting on `seq-kv`.
+#[derive(Debug)]
+enum Pending {
+    /// Reading the current value, to add `delta` to it.
+    AddRead {
+        request: Message<Payload>,
+        delta: i64,
+    },
+    /// Swapping in the new value, retried from the read when another node got in between.
+    AddCas {
+        request: Message<Payload>,
+        delta: i64,
+    },
+    /// Writing a unique value, so the following read is not served from a stale state.
+    SyncWrite {
+        request: Message<Payload>,
+    },
+    Read {
+        request: Message<Payload>,
+    },
+}
+
+/// Counter stored in `seq-kv`, adds are applied using compare-and-set.
 #[derive(Debug)]
-struct GCounterNode {
+struct SeqKvCounterNode {
     tx: mpsc::Sender<Message<Payload>>,
     rx: mpsc::Receiver<Message<Payload>>,
     node_id: String,
     msg_id: usize,
-    nodes: Vec<String>,
+    /// Requests to `seq-kv` by id, with the time they were send.
+    pending: HashMap<usize, (Instant, Pending)>,
 }
 
-impl Node<Payload> for GCounterNode {
+impl Node<Payload> for SeqKvCounterNode {
     fn initialize(
         tx: mpsc::Sender<Message<Payload>>,
         rx: mpsc::Receiver<Message<Payload>>,
         node_id: String,
-        other: Vec<String>,
+        _other: Vec<String>,
     ) -> Self {
         Self {
             tx,
             rx,
             node_id,
             msg_id: 0,
-            nodes: other,
+            pending: HashMap::with_capacity(16),
         }
     }
 
     fn run(&mut self) -> Result<()> {
-        let mut backlog = VecDeque::<Message<Payload>>::with_capacity(16);
+        loop {
+            self.expire_requests()?;
 
-        let mut value = 0;
-        let mut other_values = HashMap::with_capacity(self.nodes.len() - 1);
-        let mut last_replication_value = 0;
+            let msg = match self.rx.recv_timeout(Duration::from_millis(50)) {
+                Ok(msg) => msg,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
 
-        loop {
-            if let Some(msg) = backlog.pop_front() {
-                match msg.body.payload {
-                    Payload::Add { delta } => {
-                        value += delta;
-
-                        let ack_msg =
-                            self.generate_message(Payload::AddOk, msg.src, msg.body.msg_id);
-                        self.tx.send(ack_msg.clone())?;
-                    }
-                    Payload::Read => {
-                        let total_value = value + other_values.values().sum::<usize>();
-
-                        let ack_msg = self.generate_message(
-                            Payload::ReadOk { value: total_value },
-                            msg.src,
-                            msg.body.msg_id,
-                        );
-                        self.tx.send(ack_msg)?;
-                    }
-                    Payload::Replicate { value } => {
-                        other_values.insert(msg.src, value);
-                    }
-                    m => bail!("Invalid message for client: {m:?}"),
+            match msg.body.payload {
+                Payload::Add { delta: 0 } => {
+                    self.reply(&msg, Payload::AddOk)?;
+                }
+                Payload::Add { delta } => {
+                    self.send_kv(
+                        Payload::Read {
+                            key: Some(COUNTER_KEY.to_string()),
+                        },
+                        Pending::AddRead {
+                            request: msg,
+                            delta,
+                        },
+                    )?;
                 }
+                Payload::Read { key: None } => {
+                    self.sync(msg)?;
+                }
+                _ => {
+                    let Some((_, pending)) =
+                        msg.body.in_reply_to.and_then(|id| self.pending.remove(&id))
+                    else {
+                        // A late reply to a request which already timed out.
+                        if msg.src == SEQ_KV {
+                            continue;
+                        }
+                        bail!("Invalid message for client: {msg:?}");
+                    };
 
-                continue;
+                    self.handle_kv_response(msg.body.payload, pending)?;
+                }
             }
+        }
 
-            if let Ok(msg) = self.rx.recv_timeout(Duration::from_millis(500)) {
-                backlog.push_back(msg);
-                continue;
+        Ok(())
+    }
+}
+
+impl SeqKvCounterNode {
+    fn get_and_increment_id(&mut self) -> usize {
+        let old = self.msg_id;
+        self.msg_id += 1;
+        old
+    }
+
+    fn handle_kv_response(&mut self, payload: Payload, pending: Pending) -> Result<()> {
+        match (payload, pending) {
+            (Payload::ReadOk { value }, Pending::AddRead { request, delta }) => {
+                self.send_cas(value, request, delta)?;
+            }
+            (Payload::Error { code, .. }, Pending::AddRead { request, delta })
+                if code == KEY_DOES_NOT_EXIST =>
+            {
+                self.send_cas(0, request, delta)?;
+            }
+            (Payload::CasOk, Pending::AddCas { request, .. }) => {
+                self.reply(&request, Payload::AddOk)?;
+            }
+            (Payload::Error { code, .. }, Pending::AddCas { request, delta })
+                if code == PRECONDITION_FAILED =>
+            {
+                self.send_kv(
+                    Payload::Read {
+                        key: Some(COUNTER_KEY.to_string()),
+                    },
+                    Pending::AddRead { request, delta },
+                )?;
+            }
+            (Payload::WriteOk, Pending::SyncWrite { request }) => {
+                self.send_kv(
+                    Payload::Read {
+                        key: Some(COUNTER_KEY.to_string()),
+                    },
+                    Pending::Read { request },
+                )?;
+            }
+            (Payload::ReadOk { value }, Pending::Read { request }) => {
+                self.reply(&request, Payload::ReadOk { value })?;
+            }
+            (Payload::Error { code, .. }, Pending::Read { request })
+                if code == KEY_DOES_NOT_EXIST =>
+            {
+                self.reply(&request, Payload::ReadOk { value: 0 })?;
             }
+            // Errors of `seq-kv`, such as temporarily unavailable, mean the request did not
+            // take effect, so the step is safe to retry.
+            (Payload::Error { .. }, pending) => self.retry(pending)?,
+            (payload, pending) => {
+                bail!("Unexpected response {payload:?} from {SEQ_KV} for {pending:?}")
+            }
+        }
 
-            if last_replication_value != value {
-                for id in &self.nodes.clone() {
-                    if id == &self.node_id {
-                        continue;
-                    }
+        Ok(())
+    }
 
-                    let replicate_msg =
-                        self.generate_message(Payload::Replicate { value }, id, None);
-                    self.tx.send(replicate_msg)?;
-                }
+    /// Starts a request over from its first request to `seq-kv`.
+    fn retry(&mut self, pending: Pending) -> Result<()> {
+        match pending {
+            Pending::AddRead { request, delta } | Pending::AddCas { request, delta } => self
+                .send_kv(
+                    Payload::Read {
+                        key: Some(COUNTER_KEY.to_string()),
+                    },
+                    Pending::AddRead { request, delta },
+                ),
+            Pending::SyncWrite { request } | Pending::Read { request } => self.sync(request),
+        }
+    }
+
+    /// Retries requests `seq-kv` did not reply to in time. A compare-and-set may have been
+    /// applied, so the client is told the add timed out instead of risking adding twice.
+    fn expire_requests(&mut self) -> Result<()> {
+        let expired = self
+            .pending
+            .iter()
+            .filter(|(_, (send_at, _))| send_at.elapsed() >= KV_TIMEOUT)
+            .map(|(msg_id, _)| *msg_id)
+            .collect::<Vec<_>>();
 
-                last_replication_value = value;
+        for msg_id in expired {
+            let Some((_, pending)) = self.pending.remove(&msg_id) else {
+                continue;
+            };
+
+            match pending {
+                Pending::AddCas { request, .. } => {
+                    let error = Payload::Error {
+                        code: TIMEOUT,
+                        text: format!("No reply from {SEQ_KV}"),
+                    };
+                    self.reply(&request, error)?;
+                }
+                pending => self.retry(pending)?,
             }
         }
+
+        Ok(())
+    }
+
+    /// Writes a unique value before reading the counter for a client.
+    fn sync(&mut self, request: Message<Payload>) -> Result<()> {
+        let write = Payload::Write {
+            key: format!("sync-{}", self.node_id),
+            value: self.msg_id as i64,
+        };
+        self.send_kv(write, Pending::SyncWrite { request })
+    }
+
+    fn send_cas(&mut self, current: i64, request: Message<Payload>, delta: i64) -> Result<()> {
+        self.send_kv(
+            Payload::Cas {
+                key: COUNTER_KEY.to_string(),
+                from: current,
+                to: current + delta,
+                create_if_not_exists: true,
+            },
+            Pending::AddCas { request, delta },
+        )
+    }
+
+    fn send_kv(&mut self, payload: Payload, pending: Pending) -> Result<()> {
+        let msg_id = self.get_and_increment_id();
+        self.pending.insert(msg_id, (Instant::now(), pending));
+
+        self.tx.send(Message {
+            src: self.node_id.clone(),
+            dest: SEQ_KV.to_string(),
+            body: Body {
+                msg_id: Some(msg_id),
+                in_reply_to: None,
+                payload,
+            },
+        })?;
+
+        Ok(())
+    }
+
+    fn reply(&mut self, request: &Message<Payload>, payload: Payload) -> Result<()> {
+        let msg_id = self.get_and_increment_id();
+
+        self.tx.send(Message {
+            src: self.node_id.clone(),
+            dest: request.src.clone(),
+            body: Body {
+                msg_id: Some(msg_id),
+                in_reply_to: request.body.msg_id,
+                payload,
+            },
+        })?;
+
+        Ok(())
     }
 }
 
-impl GCounterNode {
-    fn get_and_increment_id(&mut self) -> usize {
-        let old = self.msg_id;
-        self.msg_id += 1;
-        old
+fn main() -> Result<()> {
+    match std::env::var(MODE_VAR).as_deref() {
+        Ok(SEQ_KV) => dist_sys::run_dist_sys::<SeqKvCounterNode, Payload>()?,
+        _ => dist_sys::run_dist_sys::<CrdtNode<CounterWorkload>, CrdtPayload<CounterWorkload>>()?,
     }
 
-    fn generate_message<S: ToString>(
-        &mut self,
-        payload: Payload,
-        dest: S,
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use super::*;
+
+    fn message(
+        src: &str,
+        dest: &str,
+        msg_id: usize,
         in_reply_to: Option<usize>,
+        payload: Payload,
     ) -> Message<Payload> {
         Message {
-            src: self.node_id.clone(),
+            src: src.to_string(),
             dest: dest.to_string(),
             body: Body {
-                msg_id: Some(self.get_and_increment_id()),
+                msg_id: Some(msg_id),
                 in_reply_to,
                 payload,
             },
         }
     }
-}
 
-fn main() -> Result<()> {
-    dist_sys::run_dist_sys::<GCounterNode, Payload>()?;
-    Ok(())
+    #[test]
+    fn seq_kv_add_retries_stale_cas() {
+        let (in_tx, in_rx) = mpsc::channel::<Message<Payload>>();
+        let (out_tx, out_rx) = mpsc::channel::<Message<Payload>>();
+
+        thread::spawn(move || {
+            let mut node = SeqKvCounterNode::initialize(out_tx, in_rx, "n1".to_string(), vec![]);
+            node.run().unwrap();
+        });
+
+        let recv = || {
+            out_rx
+                .recv_timeout(Duration::from_millis(500))
+                .expect("Failed to get a response in a reasonable time")
+        };
+
+        in_tx
+            .send(message("c1", "n1", 1, None, Payload::Add { delta: 2 }))
+            .unwrap();
+
+        let read = recv();
+        assert_eq!(read.dest, SEQ_KV);
+        in_tx
+            .send(message(
+                SEQ_KV,
+                "n1",
+                10,
+                read.body.msg_id,
+                Payload::ReadOk { value: 5 },
+            ))
+            .unwrap();
+
+        let cas = recv();
+        assert!(matches!(
+            cas.body.payload,
+            Payload::Cas { from: 5, to: 7, .. }
+        ));
+        in_tx
+            .send(message(
+                SEQ_KV,
+                "n1",
+                11,
+                cas.body.msg_id,
+                Payload::Error {
+                    code: PRECONDITION_FAILED,
+                    text: "stale".to_string(),
+                },
+            ))
+            .unwrap();
+
+        let read = recv();
+        in_tx
+            .send(message(
+                SEQ_KV,
+                "n1",
+                12,
+                read.body.msg_id,
+                Payload::ReadOk { value: 6 },
+            ))
+            .unwrap();
+
+        let cas = recv();
+        assert!(matches!(
+            cas.body.payload,
+            Payload::Cas { from: 6, to: 8, .. }
+        ));
+        in_tx
+            .send(message(SEQ_KV, "n1", 13, cas.body.msg_id, Payload::CasOk))
+            .unwrap();
+
+        let ack = recv();
+        assert_eq!(ack.dest, "c1");
+        assert_eq!(ack.body.in_reply_to, Some(1));
+        assert!(matches!(ack.body.payload, Payload::AddOk));
+    }
+
+    #[test]
+    fn seq_kv_errors_are_retried() {
+        let (in_tx, in_rx) = mpsc::channel::<Message<Payload>>();
+        let (out_tx, out_rx) = mpsc::channel::<Message<Payload>>();
+
+        thread::spawn(move || {
+            let mut node = SeqKvCounterNode::initialize(out_tx, in_rx, "n1".to_string(), vec![]);
+            node.run().unwrap();
+        });
+
+        let recv = |timeout| {
+            out_rx
+                .recv_timeout(timeout)
+                .expect("Failed to get a response in a reasonable time")
+        };
+        let unavailable = || Payload::Error {
+            code: 11,
+            text: "temporarily unavailable".to_string(),
+        };
+
+        in_tx
+            .send(message("c1", "n1", 1, None, Payload::Add { delta: 2 }))
+            .unwrap();
+
+        let read = recv(Duration::from_millis(500));
+        in_tx
+            .send(message(SEQ_KV, "n1", 10, read.body.msg_id, unavailable()))
+            .unwrap();
+
+        let read = recv(Duration::from_millis(500));
+        assert!(matches!(read.body.payload, Payload::Read { .. }));
+        in_tx
+            .send(message(
+                SEQ_KV,
+                "n1",
+                11,
+                read.body.msg_id,
+                Payload::ReadOk { value: 5 },
+            ))
+            .unwrap();
+
+        // The compare-and-set is never answered, so it may or may not have been applied.
+        let cas = recv(Duration::from_millis(500));
+        assert!(matches!(cas.body.payload, Payload::Cas { .. }));
+
+        let error = recv(KV_TIMEOUT * 2);
+        assert_eq!(error.dest, "c1");
+        assert!(matches!(
+            error.body.payload,
+            Payload::Error { code: TIMEOUT, .. }
+        ));
+
+        // Late replies are ignored.
+        in_tx
+            .send(message(SEQ_KV, "n1", 12, cas.body.msg_id, Payload::CasOk))
+            .unwrap();
+        in_tx
+            .send(message("c1", "n1", 2, None, Payload::Add { delta: 0 }))
+            .unwrap();
+        let ack = recv(Duration::from_millis(500));
+        assert_eq!(ack.body.in_reply_to, Some(2));
+    }
 }
@@ -0,0 +1,43 @@
+use anyhow::{bail, Result};
+use dist_sys::{
+    crdt::GSet,
+    gossip::{CrdtNode, CrdtPayload, Workload},
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "snake_case", tag = "type")]
+enum Payload {
+    Add { element: usize },
+    AddOk,
+    Read,
+    ReadOk { value: Vec<usize> },
+}
+
+struct GSetWorkload;
+
+impl Workload for GSetWorkload {
+    type State = GSet<usize>;
+    type Payload = Payload;
+
+    fn handle(set: &mut GSet<usize>, _node_id: &str, request: Payload) -> Result<(Payload, bool)> {
+        match request {
+            Payload::Add { element } => {
+                let changed = set.insert(element);
+                Ok((Payload::AddOk, changed))
+            }
+            Payload::Read => Ok((
+                Payload::ReadOk {
+                    value: set.iter().copied().collect(),
+                },
+                false,
+            )),
+            m => bail!("Invalid message for client: {m:?}"),
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    dist_sys::run_dist_sys::<CrdtNode<GSetWorkload>, CrdtPayload<GSetWorkload>>()?;
+    Ok(())
+}
@@ -1,38 +1,372 @@
 use std::{
-    collections::{HashMap, VecDeque},
-    sync::mpsc,
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap, HashSet, VecDeque},
+    hash::{Hash, Hasher},
+    path::Path,
+    sync::mpsc::{self, RecvTimeoutError},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 use dist_sys::{Body, Message, Node};
+use retention::RetentionPolicy;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use storage::{DiskStorage, MemoryStorage, ProducerSeq, Record, Storage};
 
-#[derive(Debug, Serialize, Deserialize)]
+mod retention;
+mod storage;
+
+const LIN_KV: &str = "lin-kv";
+
+/// Error code for requests which may or may not have taken effect.
+const TIMEOUT: usize = 0;
+
+/// `lin-kv` error code for reads of a key which does not exist.
+const KEY_DOES_NOT_EXIST: usize = 20;
+
+/// `lin-kv` error code for a compare-and-set with a stale `from` value.
+const PRECONDITION_FAILED: usize = 22;
+
+/// Time after which a request to `lin-kv` without a reply is given up on.
+const KV_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Time after which an unacknowledged replication message is send again.
+const REPLICATE_RETRY: Duration = Duration::from_millis(500);
+
+/// Interval in which a heartbeat is send to all other nodes.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Time without any message from a node after which it is declared dead, moving the keys it
+/// leads to the remaining nodes.
+const DEAD_AFTER: Duration = Duration::from_secs(2);
+
+/// Amount of newest sequence numbers per producer which are remembered to deduplicate retries.
+const PRODUCER_WINDOW: usize = 1024;
+
+/// Interval in which the retention policies are applied.
+const RETENTION_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Environment variable with the directory to keep the logs in, they are kept in memory if unset.
+const DATA_DIR_VAR: &str = "KAFKA_DATA_DIR";
+
+/// Environment variables overriding the default poll limits of the node.
+const POLL_MESSAGES_PER_KEY_VAR: &str = "KAFKA_POLL_MESSAGES_PER_KEY";
+const POLL_BYTES_PER_KEY_VAR: &str = "KAFKA_POLL_BYTES_PER_KEY";
+const POLL_MESSAGES_VAR: &str = "KAFKA_POLL_MESSAGES";
+const POLL_BYTES_VAR: &str = "KAFKA_POLL_BYTES";
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "snake_case", tag = "type")]
 enum Payload {
     Send {
         key: String,
-        msg: usize,
+        msg: Value,
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        headers: BTreeMap<String, String>,
+        /// Milliseconds since the Unix epoch, the time of the append if not given.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timestamp: Option<u64>,
+        /// Makes retries of the send return the original offset instead of appending again.
+        #[serde(flatten)]
+        producer: Option<ProducerSeq>,
     },
     SendOk {
         offset: usize,
     },
     Poll {
         offsets: HashMap<String, usize>,
+        #[serde(flatten)]
+        limits: RequestedPollLimits,
+        #[serde(default)]
+        format: PollFormat,
+        /// Time to wait for messages when none are available yet, replying empty afterwards.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timeout_ms: Option<u64>,
     },
     PollOk {
-        msgs: HashMap<String, Vec<[usize; 2]>>,
+        msgs: HashMap<String, Vec<Polled>>,
+        /// Keys with more messages available after the returned ones.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        more: Vec<String>,
     },
     CommitOffsets {
         offsets: HashMap<String, usize>,
+        /// Consumer group committing the offsets, groups track their offsets independently.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group: Option<String>,
     },
     CommitOffsetsOk,
     ListCommittedOffsets {
         keys: Vec<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group: Option<String>,
     },
     ListCommittedOffsetsOk {
         offsets: HashMap<String, usize>,
     },
+    /// Message appended by the sending node, replicated to all other nodes.
+    Replicate {
+        key: String,
+        offset: usize,
+        #[serde(flatten)]
+        record: Record,
+    },
+    ReplicateOk,
+    /// Sets the retention policy of a key, send by a client or replicated from another node.
+    SetRetention {
+        key: String,
+        #[serde(flatten)]
+        policy: RetentionPolicy,
+    },
+    SetRetentionOk,
+    /// Offset committed through another node, retried until acknowledged since a missing commit
+    /// of a group raises the offset before which messages are discarded.
+    Committed {
+        key: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group: Option<String>,
+        offset: usize,
+    },
+    CommittedOk,
+    /// Asks whether a record is, or is being, appended at an offset of which the claim by the
+    /// sender timed out.
+    Appended {
+        key: String,
+        offset: usize,
+    },
+    AppendedOk {
+        key: String,
+        offset: usize,
+        appended: bool,
+    },
+    Heartbeat,
+    Read {
+        key: String,
+    },
+    ReadOk {
+        value: usize,
+    },
+    Cas {
+        key: String,
+        from: usize,
+        to: usize,
+        create_if_not_exists: bool,
+    },
+    CasOk,
+    Error {
+        code: usize,
+        text: String,
+    },
+}
+
+/// How the messages are returned by a poll.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum PollFormat {
+    /// Pairs of offset and message, the format of Maelstrom's `kafka` workload.
+    #[default]
+    Compact,
+    /// Objects with the offset, message, headers and timestamp.
+    Extended,
+}
+
+/// Message returned by a poll, in one of the [`PollFormat`]s.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+enum Polled {
+    Compact(usize, Value),
+    Extended {
+        offset: usize,
+        msg: Value,
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        headers: BTreeMap<String, String>,
+        timestamp: u64,
+    },
+}
+
+impl Polled {
+    fn new(offset: usize, record: Record, format: PollFormat) -> Self {
+        match format {
+            PollFormat::Compact => Self::Compact(offset, record.msg),
+            PollFormat::Extended => Self::Extended {
+                offset,
+                msg: record.msg,
+                headers: record.headers,
+                timestamp: record.timestamp,
+            },
+        }
+    }
+
+    /// Length in JSON, approximating the size on the wire.
+    fn size(&self) -> usize {
+        serde_json::to_vec(self).map_or(0, |json| json.len())
+    }
+}
+
+/// Limits a client puts on a single poll, these can only lower the limits of the node.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+struct RequestedPollLimits {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_messages_per_key: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_bytes_per_key: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_messages: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_bytes: Option<usize>,
+}
+
+/// Limits on the messages returned by a poll, per key and for the whole response. Sizes are the
+/// length of the records in JSON, approximating their size on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct PollLimits {
+    messages_per_key: usize,
+    bytes_per_key: usize,
+    messages: usize,
+    bytes: usize,
+}
+
+impl Default for PollLimits {
+    fn default() -> Self {
+        Self {
+            messages_per_key: 100,
+            bytes_per_key: 64 * 1024,
+            messages: 1000,
+            bytes: 1024 * 1024,
+        }
+    }
+}
+
+impl PollLimits {
+    fn from_env() -> Self {
+        let var = |name: &str, default: usize| {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        };
+        let default = Self::default();
+
+        Self {
+            messages_per_key: var(POLL_MESSAGES_PER_KEY_VAR, default.messages_per_key),
+            bytes_per_key: var(POLL_BYTES_PER_KEY_VAR, default.bytes_per_key),
+            messages: var(POLL_MESSAGES_VAR, default.messages),
+            bytes: var(POLL_BYTES_VAR, default.bytes),
+        }
+    }
+
+    fn restrict(self, requested: RequestedPollLimits) -> Self {
+        let min =
+            |limit: usize, requested: Option<usize>| requested.map_or(limit, |r| r.min(limit));
+
+        Self {
+            messages_per_key: min(self.messages_per_key, requested.max_messages_per_key),
+            bytes_per_key: min(self.bytes_per_key, requested.max_bytes_per_key),
+            messages: min(self.messages, requested.max_messages),
+            bytes: min(self.bytes, requested.max_bytes),
+        }
+    }
+}
+
+/// Where to send the reply to a client request.
+#[derive(Debug, Clone)]
+struct Client {
+    src: String,
+    msg_id: Option<usize>,
+}
+
+impl Client {
+    fn of(msg: &Message<Payload>) -> Self {
+        Self {
+            src: msg.src.clone(),
+            msg_id: msg.body.msg_id,
+        }
+    }
+}
+
+/// Step a request is in while waiting on `lin-kv`.
+#[derive(Debug)]
+enum Pending {
+    /// Reading the next free offset of `key`.
+    OffsetRead {
+        client: Client,
+        key: String,
+        record: Record,
+    },
+    /// Claiming `offset`, retried from the read when another node claimed it first.
+    OffsetCas {
+        client: Client,
+        key: String,
+        record: Record,
+        offset: usize,
+    },
+    /// Reading the committed offset, to only move it forward.
+    CommitRead {
+        gather: usize,
+        commit: Commit,
+    },
+    /// Moving the committed offset forward, retried from the read when it changed in between.
+    CommitCas {
+        gather: usize,
+        commit: Commit,
+    },
+    ListRead {
+        gather: usize,
+        key: String,
+    },
+    /// Claiming an offset again after its claim timed out, so it can be filled with a tombstone.
+    FillCas {
+        key: String,
+        offset: usize,
+    },
+}
+
+/// Poll waiting for messages to arrive for any of its keys.
+#[derive(Debug)]
+struct ParkedPoll {
+    client: Client,
+    offsets: HashMap<String, usize>,
+    limits: PollLimits,
+    format: PollFormat,
+    deadline: Instant,
+}
+
+/// Offset to commit for a key, by a consumer group if given.
+#[derive(Debug, Clone)]
+struct Commit {
+    key: String,
+    group: Option<String>,
+    offset: usize,
+}
+
+impl Commit {
+    fn kv_key(&self) -> String {
+        commit_key(self.group.as_deref(), &self.key)
+    }
+}
+
+/// `send` forwarded to the leader of its key.
+#[derive(Debug)]
+struct Forward {
+    client: Client,
+    leader: String,
+    key: String,
+    record: Record,
+}
+
+#[derive(Debug)]
+enum GatherKind {
+    Commit,
+    List,
+}
+
+/// Request spanning multiple keys, answered once every key is handled.
+#[derive(Debug)]
+struct Gather {
+    client: Client,
+    kind: GatherKind,
+    outstanding: usize,
+    offsets: HashMap<String, usize>,
 }
 
 #[derive(Debug)]
@@ -41,8 +375,36 @@ struct KafkaNode {
     rx: mpsc::Receiver<Message<Payload>>,
     node_id: String,
     msg_id: usize,
-    messages: HashMap<String, Vec<usize>>,
-    committed_offsets: HashMap<String, usize>,
+    nodes: Vec<String>,
+    poll_limits: PollLimits,
+    storage: Box<dyn Storage>,
+    retention: HashMap<String, RetentionPolicy>,
+    /// Highest committed offset known to this node, per key and consumer group.
+    committed: HashMap<String, HashMap<Option<String>, usize>>,
+    /// Consumer groups known to this node, a group without a committed offset for a key keeps all
+    /// messages of that key.
+    groups: HashSet<Option<String>>,
+    /// Next free offset per key this node leads, a guess which is checked by the compare-and-set.
+    next_offsets: HashMap<String, usize>,
+    /// Last time a message was received, per node.
+    last_seen: HashMap<String, Instant>,
+    pending: HashMap<usize, (Instant, Pending)>,
+    forwarded: HashMap<usize, Forward>,
+    gathers: HashMap<usize, Gather>,
+    unacked: HashMap<usize, (Instant, Message<Payload>)>,
+    parked: Vec<ParkedPoll>,
+    /// Offsets of the newest sends per key and producer, by sequence number. Producers may number
+    /// their sends per key.
+    producers: HashMap<(String, String), BTreeMap<u64, usize>>,
+    /// Retries of producer sends which are still being appended, per key.
+    appending: HashMap<(String, ProducerSeq), Vec<Client>>,
+    /// Offsets of which a claim by this node timed out and which can not be claimed again, with
+    /// the peers which do not append to them. They are filled with a tombstone once no node
+    /// appends to them, so polls do not stop at them forever.
+    abandoned: HashMap<(String, usize), HashSet<String>>,
+    /// Failure to open the storage, returned by `run` so the node does not silently run without
+    /// the configured durability.
+    open_error: Option<anyhow::Error>,
 }
 
 impl Node<Payload> for KafkaNode {
@@ -50,109 +412,82 @@ impl Node<Payload> for KafkaNode {
         tx: mpsc::Sender<Message<Payload>>,
         rx: mpsc::Receiver<Message<Payload>>,
         node_id: String,
-        _other: Vec<String>,
+        mut other: Vec<String>,
     ) -> Self {
+        other.sort();
+        let last_seen = other
+            .iter()
+            .map(|node| (node.clone(), Instant::now()))
+            .collect();
+
+        let (storage, open_error) = match open_storage(&node_id) {
+            Ok(storage) => (storage, None),
+            Err(err) => (
+                Box::<MemoryStorage>::default() as Box<dyn Storage>,
+                Some(err),
+            ),
+        };
+
         Self {
             tx,
             rx,
             node_id,
             msg_id: 0,
-            messages: HashMap::with_capacity(128),
-            committed_offsets: HashMap::with_capacity(8),
+            nodes: other,
+            poll_limits: PollLimits::from_env(),
+            storage,
+            retention: HashMap::new(),
+            committed: HashMap::new(),
+            groups: HashSet::new(),
+            next_offsets: HashMap::with_capacity(64),
+            last_seen,
+            pending: HashMap::with_capacity(16),
+            forwarded: HashMap::with_capacity(16),
+            gathers: HashMap::with_capacity(8),
+            unacked: HashMap::with_capacity(16),
+            parked: Vec::with_capacity(8),
+            producers: HashMap::new(),
+            appending: HashMap::new(),
+            abandoned: HashMap::new(),
+            open_error,
         }
     }
 
     fn run(&mut self) -> Result<()> {
+        if let Some(err) = self.open_error.take() {
+            return Err(err);
+        }
+
         let mut queue = VecDeque::<Message<Payload>>::with_capacity(16);
+        let mut last_heartbeat = Instant::now();
+        let mut last_retention = Instant::now();
 
         loop {
-            if let Some(next) = queue.pop_front() {
-                match next.body.payload {
-                    Payload::Send { key, msg } => {
-                        let offset = match self.messages.get_mut(&key) {
-                            Some(send) => {
-                                let new_offset = send.len();
-                                send.push(msg);
-
-                                new_offset
-                            }
-                            None => {
-                                let mut offsets = Vec::with_capacity(128);
-                                offsets.push(msg);
-                                self.messages.insert(key.clone(), offsets);
-                                self.committed_offsets.insert(key, 0);
+            self.retry_replication()?;
+            self.expire_polls()?;
+            self.expire_requests()?;
 
-                                0
-                            }
-                        };
-
-                        let ack_msg = self.generate_message(
-                            Payload::SendOk { offset },
-                            next.src,
-                            next.body.msg_id,
-                        );
-                        self.tx.send(ack_msg)?;
-                    }
-                    Payload::Poll { offsets } => {
-                        let msgs = offsets
-                            .into_iter()
-                            .map(|(key, offset)| {
-                                let slice = self
-                                    .messages
-                                    .get(&key)
-                                    .unwrap_or(&vec![])
-                                    .iter()
-                                    .enumerate()
-                                    .skip(offset)
-                                    .take(3)
-                                    .map(|(i, v)| [i, *v])
-                                    .collect::<Vec<_>>();
-
-                                (key, slice)
-                            })
-                            .collect::<HashMap<_, _>>();
-
-                        let ack_msg = self.generate_message(
-                            Payload::PollOk { msgs },
-                            next.src,
-                            next.body.msg_id,
-                        );
-                        self.tx.send(ack_msg)?;
-                    }
-                    Payload::CommitOffsets { offsets } => {
-                        for (key, offset) in offsets {
-                            self.committed_offsets.insert(key, offset);
-                        }
+            if last_heartbeat.elapsed() >= HEARTBEAT_INTERVAL {
+                self.heartbeat()?;
+                self.reroute_forwards()?;
+                self.ask_abandoned()?;
+                last_heartbeat = Instant::now();
+            }
 
-                        let ack_msg = self.generate_message(
-                            Payload::CommitOffsetsOk,
-                            next.src,
-                            next.body.msg_id,
-                        );
-                        self.tx.send(ack_msg)?;
-                    }
-                    Payload::ListCommittedOffsets { keys } => {
-                        let offsets = keys
-                            .into_iter()
-                            .map(|key| {
-                                let offset = *self.committed_offsets.get(&key).unwrap_or(&0);
-                                (key, offset)
-                            })
-                            .collect::<HashMap<_, _>>();
-
-                        let ack_msg = self.generate_message(
-                            Payload::ListCommittedOffsetsOk { offsets },
-                            next.src,
-                            next.body.msg_id,
-                        );
-                        self.tx.send(ack_msg)?;
-                    }
-                    _ => bail!("Unexpected message reached for node: {next:?}"),
-                }
-            } else if let Ok(next) = self.rx.recv() {
-                queue.push_back(next);
-            } else {
-                break;
+            if last_retention.elapsed() >= RETENTION_INTERVAL {
+                self.apply_retention()?;
+                last_retention = Instant::now();
+            }
+
+            if let Some(next) = queue.pop_front() {
+                self.handle(next)?;
+                continue;
+            }
+
+            match self.rx.recv_timeout(Duration::from_millis(50)) {
+                Ok(next) => queue.push_back(next),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
             }
         }
 
@@ -183,9 +518,1723 @@ impl KafkaNode {
             },
         }
     }
-}
 
-fn main() -> Result<()> {
-    dist_sys::run_dist_sys::<KafkaNode, Payload>()?;
-    Ok(())
+    fn handle(&mut self, next: Message<Payload>) -> Result<()> {
+        let client = Client::of(&next);
+
+        if let Some(seen) = self.last_seen.get_mut(&next.src) {
+            *seen = Instant::now();
+        }
+
+        match next.body.payload {
+            Payload::Send {
+                key,
+                msg,
+                headers,
+                timestamp,
+                producer,
+            } => {
+                if let Some(offset) = producer.as_ref().and_then(|p| self.appended(&key, p)) {
+                    return self.reply(&client, Payload::SendOk { offset });
+                }
+
+                let record = Record {
+                    msg,
+                    headers,
+                    timestamp: timestamp.unwrap_or_else(unix_millis),
+                    producer,
+                    tombstone: false,
+                };
+                let leader = self.leader(&key);
+
+                // Sends forwarded by another node are appended here, even if this node no longer
+                // considers itself the leader, the compare-and-set keeps the offsets unique.
+                if leader == self.node_id || self.last_seen.contains_key(&next.src) {
+                    self.append_send(client, key, record)?;
+                } else {
+                    self.forward(Forward {
+                        client,
+                        leader,
+                        key,
+                        record,
+                    })?;
+                }
+            }
+            Payload::Poll {
+                offsets,
+                limits,
+                format,
+                timeout_ms,
+            } => {
+                let limits = self.poll_limits.restrict(limits);
+                let reply = self.poll(offsets.clone(), limits, format)?;
+
+                match timeout_ms {
+                    Some(timeout_ms) if is_empty_poll(&reply) => self.parked.push(ParkedPoll {
+                        client,
+                        offsets,
+                        limits,
+                        format,
+                        deadline: Instant::now() + Duration::from_millis(timeout_ms),
+                    }),
+                    _ => self.reply(&client, reply)?,
+                }
+            }
+            Payload::CommitOffsets { offsets, group } => {
+                let gather = self.gather(client, GatherKind::Commit, offsets.len())?;
+
+                for (key, offset) in offsets {
+                    let commit = Commit {
+                        key,
+                        group: group.clone(),
+                        offset,
+                    };
+                    self.read_commit(gather, commit)?;
+                }
+            }
+            Payload::ListCommittedOffsets { keys, group } => {
+                let gather = self.gather(client, GatherKind::List, keys.len())?;
+
+                for key in keys {
+                    self.send_kv(
+                        Payload::Read {
+                            key: commit_key(group.as_deref(), &key),
+                        },
+                        Pending::ListRead { gather, key },
+                    )?;
+                }
+            }
+            Payload::Replicate {
+                key,
+                offset,
+                record,
+            } => {
+                self.remember_producer(&key, offset, &record);
+
+                if offset >= self.storage.watermarks(&key).start {
+                    self.storage.insert(&key, offset, &record)?;
+                    self.wake_polls(&key)?;
+                }
+
+                self.reply(&client, Payload::ReplicateOk)?;
+            }
+            Payload::SetRetention { key, policy } => {
+                if !self.last_seen.contains_key(&next.src) {
+                    self.send_to_peers(Payload::SetRetention {
+                        key: key.clone(),
+                        policy: policy.clone(),
+                    })?;
+                }
+
+                self.retention.insert(key, policy);
+                self.reply(&client, Payload::SetRetentionOk)?;
+            }
+            Payload::ReplicateOk | Payload::SetRetentionOk | Payload::CommittedOk => {
+                if let Some(msg_id) = next.body.in_reply_to {
+                    self.unacked.remove(&msg_id);
+                }
+            }
+            Payload::Committed { key, group, offset } => {
+                self.learn_commit(&Commit { key, group, offset });
+                self.reply(&client, Payload::CommittedOk)?;
+            }
+            Payload::Appended { key, offset } => {
+                let appended = self.appends_at(&key, offset)?;
+                self.reply(
+                    &client,
+                    Payload::AppendedOk {
+                        key,
+                        offset,
+                        appended,
+                    },
+                )?;
+            }
+            Payload::AppendedOk {
+                key,
+                offset,
+                appended,
+            } => {
+                if let Some(answered) = self.abandoned.get_mut(&(key.clone(), offset)) {
+                    if !appended {
+                        answered.insert(next.src);
+                    }
+                }
+
+                self.try_fill(key, offset)?;
+            }
+            Payload::Heartbeat => {}
+            payload => {
+                if let Some(forward) = next
+                    .body
+                    .in_reply_to
+                    .and_then(|msg_id| self.forwarded.remove(&msg_id))
+                {
+                    return self.reply(&forward.client, payload);
+                }
+
+                let Some((_, pending)) = next
+                    .body
+                    .in_reply_to
+                    .and_then(|msg_id| self.pending.remove(&msg_id))
+                else {
+                    // Late replies to requests which timed out are ignored.
+                    if next.src == LIN_KV {
+                        return Ok(());
+                    }
+
+                    bail!("Unexpected message reached for node: {payload:?}");
+                };
+
+                self.handle_kv_response(payload, pending)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle_kv_response(&mut self, payload: Payload, pending: Pending) -> Result<()> {
+        match (payload, pending) {
+            (
+                Payload::ReadOk { value },
+                Pending::OffsetRead {
+                    client,
+                    key,
+                    record,
+                },
+            ) => {
+                self.claim_offset(client, key, record, value)?;
+            }
+            (
+                Payload::Error { code, .. },
+                Pending::OffsetRead {
+                    client,
+                    key,
+                    record,
+                },
+            ) if code == KEY_DOES_NOT_EXIST => {
+                self.claim_offset(client, key, record, 0)?;
+            }
+            (
+                Payload::CasOk,
+                Pending::OffsetCas {
+                    client,
+                    key,
+                    record,
+                    offset,
+                },
+            ) => {
+                self.next_offsets.insert(key.clone(), offset + 1);
+                self.remember_producer(&key, offset, &record);
+                self.storage.insert(&key, offset, &record)?;
+                self.wake_polls(&key)?;
+
+                if let Some(producer) = record.producer.clone() {
+                    for retry in self
+                        .appending
+                        .remove(&(key.clone(), producer))
+                        .unwrap_or_default()
+                    {
+                        self.reply(&retry, Payload::SendOk { offset })?;
+                    }
+                }
+
+                self.send_to_peers(Payload::Replicate {
+                    key,
+                    offset,
+                    record,
+                })?;
+                self.reply(&client, Payload::SendOk { offset })?;
+            }
+            (
+                Payload::Error { code, .. },
+                Pending::OffsetCas {
+                    client,
+                    key,
+                    record,
+                    ..
+                },
+            ) if code == PRECONDITION_FAILED => {
+                self.next_offsets.remove(&key);
+                self.send_kv(
+                    Payload::Read {
+                        key: offset_key(&key),
+                    },
+                    Pending::OffsetRead {
+                        client,
+                        key,
+                        record,
+                    },
+                )?;
+            }
+            (Payload::ReadOk { value }, Pending::CommitRead { gather, commit })
+                if value >= commit.offset =>
+            {
+                self.learn_commit(&Commit {
+                    offset: value,
+                    ..commit
+                });
+                self.gathered(gather)?;
+            }
+            (Payload::ReadOk { value }, Pending::CommitRead { gather, commit }) => {
+                self.advance_commit(gather, commit, value)?;
+            }
+            (Payload::Error { code, .. }, Pending::CommitRead { gather, commit })
+                if code == KEY_DOES_NOT_EXIST =>
+            {
+                self.advance_commit(gather, commit, 0)?;
+            }
+            (Payload::CasOk, Pending::CommitCas { gather, commit }) => {
+                self.learn_commit(&commit);
+                self.send_to_peers(Payload::Committed {
+                    key: commit.key,
+                    group: commit.group,
+                    offset: commit.offset,
+                })?;
+                self.gathered(gather)?;
+            }
+            (Payload::Error { code, .. }, Pending::CommitCas { gather, commit })
+                if code == PRECONDITION_FAILED =>
+            {
+                self.read_commit(gather, commit)?;
+            }
+            (Payload::ReadOk { value }, Pending::ListRead { gather, key }) => {
+                if let Some(gather) = self.gathers.get_mut(&gather) {
+                    gather.offsets.insert(key, value);
+                }
+
+                self.gathered(gather)?;
+            }
+            (Payload::Error { code, .. }, Pending::ListRead { gather, .. })
+                if code == KEY_DOES_NOT_EXIST =>
+            {
+                self.gathered(gather)?;
+            }
+            (Payload::CasOk, Pending::FillCas { key, offset }) => self.fill(key, offset)?,
+            (Payload::Error { code, .. }, Pending::FillCas { key, offset })
+                if code == PRECONDITION_FAILED =>
+            {
+                self.abandoned.entry((key.clone(), offset)).or_default();
+                self.ask_abandoned()?;
+            }
+            // The claim is retried even if it may have succeeded, as only tombstones are written
+            // to an offset claimed this way.
+            (Payload::Error { .. }, Pending::FillCas { key, offset }) => {
+                self.claim_abandoned(key, offset)?;
+            }
+            (Payload::Error { code, text }, pending) => {
+                // A claim without a reply may have succeeded, leaving the offset empty.
+                if let Pending::OffsetCas { key, offset, .. } = &pending {
+                    if code == TIMEOUT {
+                        self.next_offsets.remove(key);
+                        self.claim_abandoned(key.clone(), *offset)?;
+                    }
+                }
+
+                let client = match pending {
+                    Pending::OffsetRead {
+                        client,
+                        key,
+                        record,
+                    }
+                    | Pending::OffsetCas {
+                        client,
+                        key,
+                        record,
+                        ..
+                    } => {
+                        if let Some(producer) = record.producer {
+                            for retry in self.appending.remove(&(key, producer)).unwrap_or_default()
+                            {
+                                let payload = Payload::Error {
+                                    code,
+                                    text: text.clone(),
+                                };
+                                self.reply(&retry, payload)?;
+                            }
+                        }
+
+                        Some(client)
+                    }
+                    Pending::CommitRead { gather, .. }
+                    | Pending::CommitCas { gather, .. }
+                    | Pending::ListRead { gather, .. } => {
+                        self.gathers.remove(&gather).map(|gather| gather.client)
+                    }
+                    Pending::FillCas { .. } => None,
+                };
+
+                if let Some(client) = client {
+                    self.reply(&client, Payload::Error { code, text })?;
+                }
+            }
+            (payload, pending) => {
+                bail!("Unexpected response {payload:?} from {LIN_KV} for {pending:?}")
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads the logs from the requested offsets within `limits`, stopping at the first offset
+    /// which is not yet replicated to this node. Offsets discarded by retention and tombstones are
+    /// skipped, so a consumer continues at the next available offset. The first message is always returned, so
+    /// a message larger than the byte limits does not block the consumer.
+    fn poll(
+        &self,
+        offsets: HashMap<String, usize>,
+        limits: PollLimits,
+        format: PollFormat,
+    ) -> Result<Payload> {
+        let mut offsets = offsets.into_iter().collect::<Vec<_>>();
+        offsets.sort();
+
+        let mut msgs = HashMap::with_capacity(offsets.len());
+        let mut more = vec![];
+        let (mut messages, mut bytes) = (0, 0);
+
+        for (key, offset) in offsets {
+            let mut slice = vec![];
+            let mut key_bytes = 0;
+
+            let watermarks = self.storage.watermarks(&key);
+            let mut offset = offset.max(watermarks.start);
+
+            loop {
+                let Some(record) = self.storage.get(&key, offset)? else {
+                    if offset < watermarks.cleaned {
+                        offset += 1;
+                        continue;
+                    }
+
+                    break;
+                };
+
+                if record.tombstone {
+                    offset += 1;
+                    continue;
+                }
+
+                let polled = Polled::new(offset, record, format);
+                let size = polled.size();
+                let fits_bytes =
+                    key_bytes + size <= limits.bytes_per_key && bytes + size <= limits.bytes;
+
+                if slice.len() >= limits.messages_per_key
+                    || messages >= limits.messages
+                    || (!fits_bytes && messages > 0)
+                {
+                    more.push(key.clone());
+                    break;
+                }
+
+                slice.push(polled);
+                key_bytes += size;
+                bytes += size;
+                messages += 1;
+                offset += 1;
+            }
+
+            msgs.insert(key, slice);
+        }
+
+        Ok(Payload::PollOk { msgs, more })
+    }
+
+    /// Replies to the parked polls of `key` which now have messages available.
+    fn wake_polls(&mut self, key: &str) -> Result<()> {
+        let mut index = 0;
+
+        while index < self.parked.len() {
+            let parked = &self.parked[index];
+            if !parked.offsets.contains_key(key) {
+                index += 1;
+                continue;
+            }
+
+            let reply = self.poll(parked.offsets.clone(), parked.limits, parked.format)?;
+            if is_empty_poll(&reply) {
+                index += 1;
+                continue;
+            }
+
+            let parked = self.parked.swap_remove(index);
+            self.reply(&parked.client, reply)?;
+        }
+
+        Ok(())
+    }
+
+    /// Replies to the parked polls past their deadline, which are empty unless messages arrived
+    /// without waking them.
+    fn expire_polls(&mut self) -> Result<()> {
+        let now = Instant::now();
+        let (expired, parked) = std::mem::take(&mut self.parked)
+            .into_iter()
+            .partition::<Vec<_>, _>(|parked| parked.deadline <= now);
+        self.parked = parked;
+
+        for parked in expired {
+            let reply = self.poll(parked.offsets, parked.limits, parked.format)?;
+            self.reply(&parked.client, reply)?;
+        }
+
+        Ok(())
+    }
+
+    /// Appends a send to the log of `key`, skipping the read of the next offset when it is
+    /// cached from an earlier send.
+    fn append_send(&mut self, client: Client, key: String, record: Record) -> Result<()> {
+        if let Some(producer) = &record.producer {
+            if let Some(offset) = self.appended(&key, producer) {
+                return self.reply(&client, Payload::SendOk { offset });
+            }
+
+            let appending = (key.clone(), producer.clone());
+            if let Some(retries) = self.appending.get_mut(&appending) {
+                retries.push(client);
+                return Ok(());
+            }
+
+            self.appending.insert(appending, vec![]);
+        }
+
+        match self.next_offsets.get(&key) {
+            Some(offset) => self.claim_offset(client, key.clone(), record, *offset),
+            None => self.send_kv(
+                Payload::Read {
+                    key: offset_key(&key),
+                },
+                Pending::OffsetRead {
+                    client,
+                    key,
+                    record,
+                },
+            ),
+        }
+    }
+
+    /// Offset of an earlier send to `key` by the producer with the same sequence number.
+    fn appended(&self, key: &str, producer: &ProducerSeq) -> Option<usize> {
+        self.producers
+            .get(&(key.to_string(), producer.producer_id.clone()))
+            .and_then(|sends| sends.get(&producer.seq))
+            .copied()
+    }
+
+    fn remember_producer(&mut self, key: &str, offset: usize, record: &Record) {
+        let Some(producer) = &record.producer else {
+            return;
+        };
+
+        let sends = self
+            .producers
+            .entry((key.to_string(), producer.producer_id.clone()))
+            .or_default();
+        sends.insert(producer.seq, offset);
+
+        while sends.len() > PRODUCER_WINDOW {
+            sends.pop_first();
+        }
+    }
+
+    fn is_alive(&self, node: &str) -> bool {
+        node == self.node_id
+            || self
+                .last_seen
+                .get(node)
+                .is_some_and(|seen| seen.elapsed() < DEAD_AFTER)
+    }
+
+    /// Node owning the appends to `key`, picked from the nodes which are alive by the hash of the
+    /// key.
+    fn leader(&self, key: &str) -> String {
+        let alive = self
+            .nodes
+            .iter()
+            .filter(|node| self.is_alive(node))
+            .collect::<Vec<_>>();
+
+        if alive.is_empty() {
+            return self.node_id.clone();
+        }
+
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        alive[(hasher.finish() % alive.len() as u64) as usize].clone()
+    }
+
+    fn forward(&mut self, forward: Forward) -> Result<()> {
+        let forward_msg = self.generate_message(
+            Payload::Send {
+                key: forward.key.clone(),
+                msg: forward.record.msg.clone(),
+                headers: forward.record.headers.clone(),
+                timestamp: Some(forward.record.timestamp),
+                producer: forward.record.producer.clone(),
+            },
+            forward.leader.clone(),
+            None,
+        );
+        let msg_id = forward_msg
+            .body
+            .msg_id
+            .expect("Generated messages have an id");
+
+        self.forwarded.insert(msg_id, forward);
+        self.tx.send(forward_msg)?;
+
+        Ok(())
+    }
+
+    /// Forwards sends again when their leader was declared dead before replying. The dead leader
+    /// may have appended the send already, so only sends of a producer, which are deduplicated,
+    /// are retried, all others fail with an indeterminate error.
+    fn reroute_forwards(&mut self) -> Result<()> {
+        let dead = self
+            .forwarded
+            .iter()
+            .filter(|(_, forward)| !self.is_alive(&forward.leader))
+            .map(|(msg_id, _)| *msg_id)
+            .collect::<Vec<_>>();
+
+        for msg_id in dead {
+            let Some(mut forward) = self.forwarded.remove(&msg_id) else {
+                continue;
+            };
+
+            if forward.record.producer.is_none() {
+                let payload = Payload::Error {
+                    code: TIMEOUT,
+                    text: format!(
+                        "Leader {} of {} stopped responding",
+                        forward.leader, forward.key
+                    ),
+                };
+                self.reply(&forward.client, payload)?;
+                continue;
+            }
+
+            forward.leader = self.leader(&forward.key);
+
+            if forward.leader == self.node_id {
+                self.append_send(forward.client, forward.key, forward.record)?;
+            } else {
+                self.forward(forward)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn heartbeat(&mut self) -> Result<()> {
+        for node in self.nodes.clone() {
+            if node != self.node_id {
+                let heartbeat = self.generate_message(Payload::Heartbeat, node, None);
+                self.tx.send(heartbeat)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn claim_offset(
+        &mut self,
+        client: Client,
+        key: String,
+        record: Record,
+        offset: usize,
+    ) -> Result<()> {
+        self.send_kv(
+            Payload::Cas {
+                key: offset_key(&key),
+                from: offset,
+                to: offset + 1,
+                create_if_not_exists: true,
+            },
+            Pending::OffsetCas {
+                client,
+                key,
+                record,
+                offset,
+            },
+        )
+    }
+
+    /// Claims an offset again after its claim timed out, the offset is empty if either succeeded.
+    fn claim_abandoned(&mut self, key: String, offset: usize) -> Result<()> {
+        self.send_kv(
+            Payload::Cas {
+                key: offset_key(&key),
+                from: offset,
+                to: offset + 1,
+                create_if_not_exists: true,
+            },
+            Pending::FillCas { key, offset },
+        )
+    }
+
+    /// Asks the peers which did not answer yet whether they append to the abandoned offsets,
+    /// dropping the offsets which received a record in the meantime.
+    fn ask_abandoned(&mut self) -> Result<()> {
+        let abandoned = self.abandoned.keys().cloned().collect::<Vec<_>>();
+
+        for (key, offset) in abandoned {
+            if offset < self.storage.watermarks(&key).start
+                || self.storage.get(&key, offset)?.is_some()
+            {
+                self.abandoned.remove(&(key, offset));
+                continue;
+            }
+
+            let answered = &self.abandoned[&(key.clone(), offset)];
+            let unanswered = self
+                .nodes
+                .iter()
+                .filter(|node| **node != self.node_id && !answered.contains(*node))
+                .cloned()
+                .collect::<Vec<_>>();
+
+            for node in unanswered {
+                let payload = Payload::Appended {
+                    key: key.clone(),
+                    offset,
+                };
+                let msg = self.generate_message(payload, node, None);
+                self.tx.send(msg)?;
+            }
+
+            self.try_fill(key, offset)?;
+        }
+
+        Ok(())
+    }
+
+    /// Fills an abandoned offset once neither this node nor any of its peers appends to it.
+    fn try_fill(&mut self, key: String, offset: usize) -> Result<()> {
+        let Some(answered) = self.abandoned.get(&(key.clone(), offset)) else {
+            return Ok(());
+        };
+
+        let unanswered = self
+            .nodes
+            .iter()
+            .any(|node| *node != self.node_id && !answered.contains(node));
+
+        if unanswered || self.appends_at(&key, offset)? {
+            return Ok(());
+        }
+
+        self.fill(key, offset)
+    }
+
+    /// Writes a tombstone to an offset no record is appended to, and replicates it.
+    fn fill(&mut self, key: String, offset: usize) -> Result<()> {
+        self.abandoned.remove(&(key.clone(), offset));
+
+        let record = Record::tombstone(unix_millis());
+        if offset >= self.storage.watermarks(&key).start {
+            self.storage.insert(&key, offset, &record)?;
+            self.wake_polls(&key)?;
+        }
+
+        self.send_to_peers(Payload::Replicate {
+            key,
+            offset,
+            record,
+        })
+    }
+
+    /// Whether a record is stored at `offset` of `key`, or this node is claiming the offset for
+    /// one.
+    fn appends_at(&self, key: &str, offset: usize) -> Result<bool> {
+        let claiming = self.pending.values().any(|(_, pending)| {
+            matches!(
+                pending,
+                Pending::OffsetCas { key: claimed, offset: at, .. }
+                    if claimed == key && *at == offset
+            )
+        });
+        let stored = self
+            .storage
+            .get(key, offset)?
+            .is_some_and(|record| !record.tombstone);
+
+        Ok(claiming || stored)
+    }
+
+    fn read_commit(&mut self, gather: usize, commit: Commit) -> Result<()> {
+        self.send_kv(
+            Payload::Read {
+                key: commit.kv_key(),
+            },
+            Pending::CommitRead { gather, commit },
+        )
+    }
+
+    fn advance_commit(&mut self, gather: usize, commit: Commit, current: usize) -> Result<()> {
+        self.send_kv(
+            Payload::Cas {
+                key: commit.kv_key(),
+                from: current,
+                to: commit.offset,
+                create_if_not_exists: true,
+            },
+            Pending::CommitCas { gather, commit },
+        )
+    }
+
+    fn learn_commit(&mut self, commit: &Commit) {
+        self.groups.insert(commit.group.clone());
+
+        let offset = self
+            .committed
+            .entry(commit.key.clone())
+            .or_default()
+            .entry(commit.group.clone())
+            .or_default();
+
+        *offset = (*offset).max(commit.offset);
+    }
+
+    /// Discards messages of the keys with a retention policy. Only messages before the lowest
+    /// offset committed by the consumer groups known to this node are discarded, nothing is
+    /// discarded before any group committed.
+    fn apply_retention(&mut self) -> Result<()> {
+        let now = unix_millis();
+
+        for (key, policy) in &self.retention {
+            let committed = self.committed.get(key);
+            let floor = self
+                .groups
+                .iter()
+                .map(|group| {
+                    committed
+                        .and_then(|offsets| offsets.get(group))
+                        .copied()
+                        .unwrap_or_default()
+                })
+                .min()
+                .unwrap_or_default();
+
+            if let Some((discard, watermarks)) =
+                retention::plan(self.storage.as_ref(), key, policy, floor, now)?
+            {
+                self.storage.discard(key, &discard, watermarks)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sends a message to all other nodes, retrying until acknowledged.
+    fn send_to_peers(&mut self, payload: Payload) -> Result<()> {
+        for node in self.nodes.clone() {
+            if node == self.node_id {
+                continue;
+            }
+
+            let replicate_msg = self.generate_message(payload.clone(), node, None);
+            self.tx.send(replicate_msg.clone())?;
+
+            let msg_id = replicate_msg
+                .body
+                .msg_id
+                .expect("Generated messages have an id");
+            self.unacked.insert(msg_id, (Instant::now(), replicate_msg));
+        }
+
+        Ok(())
+    }
+
+    /// Gives up on the requests to `lin-kv` without a reply in time, as if it replied with a
+    /// timeout. They may or may not have taken effect.
+    fn expire_requests(&mut self) -> Result<()> {
+        let expired = self
+            .pending
+            .iter()
+            .filter(|(_, (send_at, _))| send_at.elapsed() >= KV_TIMEOUT)
+            .map(|(msg_id, _)| *msg_id)
+            .collect::<Vec<_>>();
+
+        for msg_id in expired {
+            let Some((_, pending)) = self.pending.remove(&msg_id) else {
+                continue;
+            };
+
+            let error = Payload::Error {
+                code: TIMEOUT,
+                text: format!("No reply from {LIN_KV}"),
+            };
+            self.handle_kv_response(error, pending)?;
+        }
+
+        Ok(())
+    }
+
+    fn retry_replication(&mut self) -> Result<()> {
+        for (send_at, msg) in self.unacked.values_mut() {
+            if send_at.elapsed() >= REPLICATE_RETRY {
+                self.tx.send(msg.clone())?;
+                *send_at = Instant::now();
+            }
+        }
+
+        Ok(())
+    }
+
+    fn gather(&mut self, client: Client, kind: GatherKind, outstanding: usize) -> Result<usize> {
+        let id = self.get_and_increment_id();
+        self.gathers.insert(
+            id,
+            Gather {
+                client,
+                kind,
+                outstanding,
+                offsets: HashMap::new(),
+            },
+        );
+
+        if outstanding == 0 {
+            self.finish_gather(id)?;
+        }
+
+        Ok(id)
+    }
+
+    fn gathered(&mut self, id: usize) -> Result<()> {
+        let Some(gather) = self.gathers.get_mut(&id) else {
+            return Ok(());
+        };
+
+        gather.outstanding -= 1;
+
+        if gather.outstanding == 0 {
+            self.finish_gather(id)?;
+        }
+
+        Ok(())
+    }
+
+    fn finish_gather(&mut self, id: usize) -> Result<()> {
+        let Some(gather) = self.gathers.remove(&id) else {
+            return Ok(());
+        };
+
+        let payload = match gather.kind {
+            GatherKind::Commit => Payload::CommitOffsetsOk,
+            GatherKind::List => Payload::ListCommittedOffsetsOk {
+                offsets: gather.offsets,
+            },
+        };
+
+        self.reply(&gather.client, payload)
+    }
+
+    fn send_kv(&mut self, payload: Payload, pending: Pending) -> Result<()> {
+        let msg = self.generate_message(payload, LIN_KV.to_string(), None);
+        let msg_id = msg.body.msg_id.expect("Generated messages have an id");
+
+        self.pending.insert(msg_id, (Instant::now(), pending));
+        self.tx.send(msg)?;
+
+        Ok(())
+    }
+
+    fn reply(&mut self, client: &Client, payload: Payload) -> Result<()> {
+        let ack_msg = self.generate_message(payload, client.src.clone(), client.msg_id);
+        self.tx.send(ack_msg)?;
+
+        Ok(())
+    }
+}
+
+/// Opens the storage of the node, in a directory per node so nodes can share the data directory.
+fn open_storage(node_id: &str) -> Result<Box<dyn Storage>> {
+    let Ok(dir) = std::env::var(DATA_DIR_VAR) else {
+        return Ok(Box::<MemoryStorage>::default());
+    };
+
+    let dir = Path::new(&dir).join(node_id);
+    let storage = DiskStorage::open(&dir)
+        .with_context(|| format!("Failed to open the logs in {}", dir.display()))?;
+
+    Ok(Box::new(storage))
+}
+
+fn is_empty_poll(reply: &Payload) -> bool {
+    match reply {
+        Payload::PollOk { msgs, .. } => msgs.values().all(Vec::is_empty),
+        _ => false,
+    }
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+/// Key in `lin-kv` holding the next free offset of a log.
+fn offset_key(key: &str) -> String {
+    format!("offset-{key}")
+}
+
+/// Key in `lin-kv` holding the committed offset of a log, for a consumer group if given. The
+/// group is quoted, so it can not be confused with the part of a key.
+fn commit_key(group: Option<&str>, key: &str) -> String {
+    match group {
+        Some(group) => format!("commit-{group:?}-{key}"),
+        None => format!("commit-{key}"),
+    }
+}
+
+fn main() -> Result<()> {
+    dist_sys::run_dist_sys::<KafkaNode, Payload>()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeSet, thread};
+
+    use crate::storage::Watermarks;
+
+    use super::*;
+
+    fn message(
+        src: &str,
+        msg_id: usize,
+        in_reply_to: Option<usize>,
+        payload: Payload,
+    ) -> Message<Payload> {
+        Message {
+            src: src.to_string(),
+            dest: "n1".to_string(),
+            body: Body {
+                msg_id: Some(msg_id),
+                in_reply_to,
+                payload,
+            },
+        }
+    }
+
+    fn record(msg: usize) -> Record {
+        Record {
+            msg: msg.into(),
+            headers: BTreeMap::new(),
+            timestamp: 0,
+            producer: None,
+            tombstone: false,
+        }
+    }
+
+    fn send(key: impl Into<String>, msg: usize) -> Payload {
+        Payload::Send {
+            key: key.into(),
+            msg: msg.into(),
+            headers: BTreeMap::new(),
+            timestamp: None,
+            producer: None,
+        }
+    }
+
+    fn compact(records: &[[usize; 2]]) -> Vec<Polled> {
+        records
+            .iter()
+            .map(|[offset, msg]| Polled::Compact(*offset, (*msg).into()))
+            .collect()
+    }
+
+    fn nodes() -> Vec<String> {
+        vec!["n1".to_string(), "n2".to_string()]
+    }
+
+    fn idle_node(node_id: &str, nodes: Vec<String>) -> KafkaNode {
+        let (tx, _) = mpsc::channel();
+        let (_, rx) = mpsc::channel();
+        KafkaNode::initialize(tx, rx, node_id.to_string(), nodes)
+    }
+
+    /// A key led by `leader` while all nodes are alive.
+    fn key_led_by(leader: &str) -> String {
+        let node = idle_node("n1", nodes());
+
+        (0..)
+            .map(|i| format!("k{i}"))
+            .find(|key| node.leader(key) == leader)
+            .unwrap()
+    }
+
+    /// Spawns node `n1`, returning a sender for its input and a receiver of its output which
+    /// skips heartbeats.
+    fn spawn() -> (
+        mpsc::Sender<Message<Payload>>,
+        impl Fn() -> Message<Payload>,
+    ) {
+        let (in_tx, in_rx) = mpsc::channel::<Message<Payload>>();
+        let (out_tx, out_rx) = mpsc::channel::<Message<Payload>>();
+
+        thread::spawn(move || {
+            let mut node = KafkaNode::initialize(out_tx, in_rx, "n1".to_string(), nodes());
+            node.run().unwrap();
+        });
+
+        let recv = move || loop {
+            let msg = out_rx
+                .recv_timeout(Duration::from_millis(500))
+                .expect("Failed to get a response in a reasonable time");
+
+            if !matches!(msg.body.payload, Payload::Heartbeat) {
+                return msg;
+            }
+        };
+
+        (in_tx, recv)
+    }
+
+    #[test]
+    fn send_claims_offset_and_replicates() {
+        let (in_tx, recv) = spawn();
+        let key = key_led_by("n1");
+
+        in_tx
+            .send(message("c1", 1, None, send(key.clone(), 42)))
+            .unwrap();
+
+        let read = recv();
+        assert_eq!(read.dest, LIN_KV);
+        in_tx
+            .send(message(
+                LIN_KV,
+                10,
+                read.body.msg_id,
+                Payload::Error {
+                    code: KEY_DOES_NOT_EXIST,
+                    text: "not found".to_string(),
+                },
+            ))
+            .unwrap();
+
+        let cas = recv();
+        assert!(matches!(
+            cas.body.payload,
+            Payload::Cas { from: 0, to: 1, .. }
+        ));
+        in_tx
+            .send(message(LIN_KV, 11, cas.body.msg_id, Payload::CasOk))
+            .unwrap();
+
+        let replicate = recv();
+        assert_eq!(replicate.dest, "n2");
+        assert!(matches!(
+            replicate.body.payload,
+            Payload::Replicate {
+                offset: 0,
+                ref record,
+                ..
+            } if record.msg == 42
+        ));
+
+        let ack = recv();
+        assert_eq!(ack.body.in_reply_to, Some(1));
+        assert!(matches!(ack.body.payload, Payload::SendOk { offset: 0 }));
+
+        in_tx
+            .send(message("c1", 2, None, send(key.clone(), 43)))
+            .unwrap();
+
+        let cas = recv();
+        assert!(
+            matches!(cas.body.payload, Payload::Cas { from: 1, to: 2, .. }),
+            "The next offset is claimed without reading it first"
+        );
+
+        in_tx
+            .send(message(
+                "c1",
+                3,
+                None,
+                Payload::Poll {
+                    offsets: HashMap::from([(key.clone(), 0)]),
+                    limits: RequestedPollLimits::default(),
+                    format: PollFormat::Compact,
+                    timeout_ms: None,
+                },
+            ))
+            .unwrap();
+
+        let Payload::PollOk { msgs, .. } = recv().body.payload else {
+            panic!("Expected a poll_ok");
+        };
+        assert_eq!(msgs[&key], compact(&[[0, 42]]));
+    }
+
+    #[test]
+    fn producer_retries_return_original_offset() {
+        let (in_tx, recv) = spawn();
+        let key = key_led_by("n1");
+
+        let send = |msg_id| {
+            let payload = Payload::Send {
+                key: key.clone(),
+                msg: 42.into(),
+                headers: BTreeMap::new(),
+                timestamp: None,
+                producer: Some(ProducerSeq {
+                    producer_id: "p1".to_string(),
+                    seq: 7,
+                }),
+            };
+            message("c1", msg_id, None, payload)
+        };
+
+        in_tx.send(send(1)).unwrap();
+        let read = recv();
+        in_tx.send(send(2)).unwrap();
+
+        in_tx
+            .send(message(
+                LIN_KV,
+                10,
+                read.body.msg_id,
+                Payload::ReadOk { value: 3 },
+            ))
+            .unwrap();
+        let cas = recv();
+        in_tx
+            .send(message(LIN_KV, 11, cas.body.msg_id, Payload::CasOk))
+            .unwrap();
+
+        let mut acked = vec![];
+        while acked.len() < 2 {
+            let msg = recv();
+            if let Payload::SendOk { offset } = msg.body.payload {
+                acked.push((msg.body.in_reply_to, offset));
+            }
+        }
+        acked.sort();
+        assert_eq!(acked, vec![(Some(1), 3), (Some(2), 3)]);
+
+        in_tx.send(send(3)).unwrap();
+        let ack = recv();
+        assert_eq!(ack.body.in_reply_to, Some(3));
+        assert!(matches!(ack.body.payload, Payload::SendOk { offset: 3 }));
+    }
+
+    #[test]
+    fn producer_seqs_are_per_key() {
+        let (tx, out_rx) = mpsc::channel();
+        let (_in_tx, rx) = mpsc::channel();
+        let mut node = KafkaNode::initialize(tx, rx, "n1".to_string(), vec![]);
+
+        for (msg_id, key) in [(1, "k1"), (2, "k2"), (3, "k1")] {
+            let payload = Payload::Send {
+                key: key.to_string(),
+                msg: msg_id.into(),
+                headers: BTreeMap::new(),
+                timestamp: None,
+                producer: Some(ProducerSeq {
+                    producer_id: "p1".to_string(),
+                    seq: 0,
+                }),
+            };
+            node.handle(message("c1", msg_id, None, payload)).unwrap();
+        }
+
+        // Serve `lin-kv` until all sends are acknowledged.
+        let mut acked = vec![];
+        while acked.len() < 3 {
+            let msg = out_rx.try_recv().expect("Sends should be acknowledged");
+            let reply = match msg.body.payload {
+                Payload::Read { .. } => Payload::ReadOk { value: 0 },
+                Payload::Cas { .. } => Payload::CasOk,
+                Payload::SendOk { offset } => {
+                    acked.push((msg.body.in_reply_to, offset));
+                    continue;
+                }
+                payload => panic!("Unexpected message: {payload:?}"),
+            };
+            node.handle(message(LIN_KV, 10, msg.body.msg_id, reply))
+                .unwrap();
+        }
+        acked.sort();
+
+        assert_eq!(acked, vec![(Some(1), 0), (Some(2), 0), (Some(3), 0)]);
+        assert_eq!(node.storage.get("k2", 0).unwrap().unwrap().msg, 2);
+    }
+
+    #[test]
+    fn timed_out_claim_is_filled_with_tombstone() {
+        let (tx, out_rx) = mpsc::channel();
+        let (_in_tx, rx) = mpsc::channel();
+        let mut node = KafkaNode::initialize(tx, rx, "n1".to_string(), nodes());
+        let key = key_led_by("n1");
+
+        node.handle(message("c1", 1, None, send(key.clone(), 5)))
+            .unwrap();
+        let read = out_rx.try_recv().unwrap();
+        node.handle(message(
+            LIN_KV,
+            10,
+            read.body.msg_id,
+            Payload::ReadOk { value: 0 },
+        ))
+        .unwrap();
+        let cas = out_rx.try_recv().unwrap();
+
+        for (send_at, _) in node.pending.values_mut() {
+            *send_at -= KV_TIMEOUT;
+        }
+        node.expire_requests().unwrap();
+
+        let mut sent = out_rx.try_iter().collect::<Vec<_>>();
+        sent.sort_by_key(|msg| msg.dest.clone());
+        let [error, fill] = sent.as_slice() else {
+            panic!("Expected an error and a new claim, got {sent:?}");
+        };
+        assert_eq!(error.dest, "c1");
+        assert!(matches!(
+            error.body.payload,
+            Payload::Error { code: TIMEOUT, .. }
+        ));
+        assert!(matches!(fill.body.payload, Payload::Cas { from: 0, .. }));
+
+        // The claim did succeed, but its reply came too late.
+        node.handle(message(LIN_KV, 11, cas.body.msg_id, Payload::CasOk))
+            .unwrap();
+        let error = Payload::Error {
+            code: PRECONDITION_FAILED,
+            text: "precondition failed".to_string(),
+        };
+        node.handle(message(LIN_KV, 12, fill.body.msg_id, error))
+            .unwrap();
+
+        let ask = out_rx.try_recv().unwrap();
+        assert_eq!(ask.dest, "n2");
+        assert!(matches!(
+            ask.body.payload,
+            Payload::Appended { offset: 0, .. }
+        ));
+
+        let answer = Payload::AppendedOk {
+            key: key.clone(),
+            offset: 0,
+            appended: false,
+        };
+        node.handle(message("n2", 13, ask.body.msg_id, answer))
+            .unwrap();
+
+        let replicate = out_rx.try_recv().unwrap();
+        assert!(matches!(
+            replicate.body.payload,
+            Payload::Replicate {
+                offset: 0,
+                record: Record {
+                    tombstone: true,
+                    ..
+                },
+                ..
+            }
+        ));
+
+        node.storage.insert(&key, 1, &record(6)).unwrap();
+        let offsets = HashMap::from([(key.clone(), 0)]);
+        let Payload::PollOk { msgs, .. } = node
+            .poll(offsets, PollLimits::default(), PollFormat::Compact)
+            .unwrap()
+        else {
+            panic!("Expected a poll_ok");
+        };
+        assert_eq!(msgs[&key], compact(&[[1, 6]]));
+    }
+
+    #[test]
+    fn long_poll_waits_for_messages() {
+        let (in_tx, recv) = spawn();
+
+        let poll = |msg_id, timeout_ms| {
+            message(
+                "c1",
+                msg_id,
+                None,
+                Payload::Poll {
+                    offsets: HashMap::from([("k1".to_string(), 0)]),
+                    limits: RequestedPollLimits::default(),
+                    format: PollFormat::Compact,
+                    timeout_ms: Some(timeout_ms),
+                },
+            )
+        };
+
+        in_tx.send(poll(1, 100)).unwrap();
+
+        let reply = recv();
+        assert_eq!(reply.body.in_reply_to, Some(1));
+        assert!(is_empty_poll(&reply.body.payload));
+
+        in_tx.send(poll(2, 5000)).unwrap();
+        in_tx
+            .send(message(
+                "n2",
+                10,
+                None,
+                Payload::Replicate {
+                    key: "k1".to_string(),
+                    offset: 0,
+                    record: record(42),
+                },
+            ))
+            .unwrap();
+
+        let reply = recv();
+        assert_eq!(reply.body.in_reply_to, Some(2));
+        let Payload::PollOk { msgs, .. } = reply.body.payload else {
+            panic!("Expected a poll_ok");
+        };
+        assert_eq!(msgs["k1"], compact(&[[0, 42]]));
+    }
+
+    #[test]
+    fn send_is_forwarded_to_leader() {
+        let (in_tx, recv) = spawn();
+
+        in_tx
+            .send(message("c1", 1, None, send(key_led_by("n2"), 42)))
+            .unwrap();
+
+        let forward = recv();
+        assert_eq!(forward.dest, "n2");
+        assert!(matches!(
+            forward.body.payload,
+            Payload::Send { ref msg, .. } if msg == 42
+        ));
+
+        in_tx
+            .send(message(
+                "n2",
+                10,
+                forward.body.msg_id,
+                Payload::SendOk { offset: 7 },
+            ))
+            .unwrap();
+
+        let ack = recv();
+        assert_eq!(ack.dest, "c1");
+        assert_eq!(ack.body.in_reply_to, Some(1));
+        assert!(matches!(ack.body.payload, Payload::SendOk { offset: 7 }));
+    }
+
+    #[test]
+    fn commits_only_move_forward() {
+        let (in_tx, recv) = spawn();
+
+        let commit = |msg_id, offset| {
+            message(
+                "c1",
+                msg_id,
+                None,
+                Payload::CommitOffsets {
+                    offsets: HashMap::from([("k1".to_string(), offset)]),
+                    group: Some("g1".to_string()),
+                },
+            )
+        };
+
+        in_tx.send(commit(1, 5)).unwrap();
+
+        let read = recv();
+        assert!(
+            matches!(read.body.payload, Payload::Read { ref key } if key == "commit-\"g1\"-k1")
+        );
+        in_tx
+            .send(message(
+                LIN_KV,
+                10,
+                read.body.msg_id,
+                Payload::ReadOk { value: 7 },
+            ))
+            .unwrap();
+
+        let ack = recv();
+        assert_eq!(ack.body.in_reply_to, Some(1));
+        assert!(matches!(ack.body.payload, Payload::CommitOffsetsOk));
+
+        in_tx.send(commit(2, 9)).unwrap();
+
+        let read = recv();
+        in_tx
+            .send(message(
+                LIN_KV,
+                11,
+                read.body.msg_id,
+                Payload::ReadOk { value: 7 },
+            ))
+            .unwrap();
+
+        let cas = recv();
+        assert!(matches!(
+            cas.body.payload,
+            Payload::Cas { from: 7, to: 9, .. }
+        ));
+        in_tx
+            .send(message(LIN_KV, 12, cas.body.msg_id, Payload::CasOk))
+            .unwrap();
+
+        let committed = recv();
+        assert_eq!(committed.dest, "n2");
+        assert!(matches!(
+            committed.body.payload,
+            Payload::Committed { offset: 9, .. }
+        ));
+
+        let ack = recv();
+        assert_eq!(ack.body.in_reply_to, Some(2));
+        assert!(matches!(ack.body.payload, Payload::CommitOffsetsOk));
+    }
+
+    #[test]
+    fn retention_keeps_messages_of_groups_without_commit() {
+        let mut node = idle_node("n1", vec![]);
+
+        for offset in 0..4 {
+            node.storage.insert("k1", offset, &record(offset)).unwrap();
+        }
+        node.retention.insert(
+            "k1".to_string(),
+            RetentionPolicy {
+                max_messages: Some(1),
+                ..Default::default()
+            },
+        );
+
+        node.learn_commit(&Commit {
+            key: "k2".to_string(),
+            group: Some("g1".to_string()),
+            offset: 3,
+        });
+        node.learn_commit(&Commit {
+            key: "k1".to_string(),
+            group: Some("g2".to_string()),
+            offset: 3,
+        });
+        node.apply_retention().unwrap();
+        assert_eq!(node.storage.watermarks("k1").start, 0);
+
+        node.learn_commit(&Commit {
+            key: "k1".to_string(),
+            group: Some("g1".to_string()),
+            offset: 2,
+        });
+        node.apply_retention().unwrap();
+        assert_eq!(node.storage.watermarks("k1").start, 2);
+    }
+
+    #[test]
+    fn keys_move_from_dead_leader() {
+        let mut node = idle_node("n1", nodes());
+        let key = key_led_by("n2");
+
+        node.last_seen.insert(
+            "n2".to_string(),
+            Instant::now() - DEAD_AFTER - Duration::from_millis(1),
+        );
+        assert_eq!(node.leader(&key), "n1");
+
+        node.last_seen.insert("n2".to_string(), Instant::now());
+        assert_eq!(node.leader(&key), "n2");
+    }
+
+    #[test]
+    fn forwards_to_dead_leader_are_only_retried_for_producers() {
+        let (tx, out_rx) = mpsc::channel();
+        let (_in_tx, rx) = mpsc::channel();
+        let mut node = KafkaNode::initialize(tx, rx, "n1".to_string(), nodes());
+        let key = key_led_by("n2");
+
+        node.handle(message("c1", 1, None, send(key.clone(), 5)))
+            .unwrap();
+        let producer = Payload::Send {
+            key: key.clone(),
+            msg: 6.into(),
+            headers: BTreeMap::new(),
+            timestamp: None,
+            producer: Some(ProducerSeq {
+                producer_id: "p1".to_string(),
+                seq: 0,
+            }),
+        };
+        node.handle(message("c1", 2, None, producer)).unwrap();
+
+        for _ in 0..2 {
+            assert_eq!(out_rx.recv().unwrap().dest, "n2");
+        }
+
+        node.last_seen.insert(
+            "n2".to_string(),
+            Instant::now() - DEAD_AFTER - Duration::from_millis(1),
+        );
+        node.reroute_forwards().unwrap();
+
+        let mut sent = out_rx.try_iter().collect::<Vec<_>>();
+        sent.sort_by_key(|msg| msg.dest.clone());
+
+        assert!(matches!(
+            sent.as_slice(),
+            [failed, appending]
+                if failed.dest == "c1"
+                    && failed.body.in_reply_to == Some(1)
+                    && matches!(failed.body.payload, Payload::Error { code: TIMEOUT, .. })
+                    && appending.dest == LIN_KV
+        ));
+    }
+
+    #[test]
+    fn poll_respects_limits() {
+        let mut node = idle_node("n1", vec![]);
+
+        for offset in 0..10 {
+            node.storage.insert("k1", offset, &record(offset)).unwrap();
+            node.storage.insert("k2", offset, &record(offset)).unwrap();
+        }
+
+        let offsets = HashMap::from([("k1".to_string(), 0), ("k2".to_string(), 8)]);
+        let limits = PollLimits::default().restrict(RequestedPollLimits {
+            max_messages_per_key: Some(4),
+            ..Default::default()
+        });
+
+        let Payload::PollOk { msgs, more } = node
+            .poll(offsets.clone(), limits, PollFormat::Compact)
+            .unwrap()
+        else {
+            panic!("Expected a poll_ok");
+        };
+        assert_eq!(msgs["k1"], compact(&[[0, 0], [1, 1], [2, 2], [3, 3]]));
+        assert_eq!(msgs["k2"], compact(&[[8, 8], [9, 9]]));
+        assert_eq!(more, vec!["k1".to_string()]);
+
+        let limits = PollLimits::default().restrict(RequestedPollLimits {
+            max_messages: Some(3),
+            ..Default::default()
+        });
+        let Payload::PollOk { msgs, more } = node
+            .poll(offsets.clone(), limits, PollFormat::Compact)
+            .unwrap()
+        else {
+            panic!("Expected a poll_ok");
+        };
+        assert_eq!(msgs["k1"].len(), 3);
+        assert!(msgs["k2"].is_empty());
+        assert_eq!(more, vec!["k1".to_string(), "k2".to_string()]);
+
+        let limits = PollLimits::default().restrict(RequestedPollLimits {
+            max_bytes: Some(1),
+            ..Default::default()
+        });
+        let Payload::PollOk { msgs, .. } = node.poll(offsets, limits, PollFormat::Compact).unwrap()
+        else {
+            panic!("Expected a poll_ok");
+        };
+        assert_eq!(
+            msgs["k1"],
+            compact(&[[0, 0]]),
+            "The first message is returned even if it exceeds the byte limit"
+        );
+    }
+
+    #[test]
+    fn poll_returns_extended_records() {
+        let mut node = idle_node("n1", vec![]);
+        let record = Record {
+            msg: serde_json::json!({"name": "widget"}),
+            headers: BTreeMap::from([("key".to_string(), "w1".to_string())]),
+            timestamp: 1700,
+            producer: None,
+            tombstone: false,
+        };
+        node.storage.insert("k1", 0, &record).unwrap();
+
+        let offsets = HashMap::from([("k1".to_string(), 0)]);
+        let Payload::PollOk { msgs, .. } = node
+            .poll(offsets.clone(), PollLimits::default(), PollFormat::Compact)
+            .unwrap()
+        else {
+            panic!("Expected a poll_ok");
+        };
+        assert_eq!(
+            serde_json::to_value(&msgs["k1"]).unwrap(),
+            serde_json::json!([[0, {"name": "widget"}]])
+        );
+
+        let Payload::PollOk { msgs, .. } = node
+            .poll(offsets, PollLimits::default(), PollFormat::Extended)
+            .unwrap()
+        else {
+            panic!("Expected a poll_ok");
+        };
+        assert_eq!(
+            serde_json::to_value(&msgs["k1"]).unwrap(),
+            serde_json::json!([{
+                "offset": 0,
+                "msg": {"name": "widget"},
+                "headers": {"key": "w1"},
+                "timestamp": 1700,
+            }])
+        );
+    }
+
+    #[test]
+    fn poll_skips_discarded_offsets() {
+        let mut node = idle_node("n1", vec![]);
+
+        for offset in 0..5 {
+            node.storage.insert("k1", offset, &record(offset)).unwrap();
+        }
+        node.storage
+            .discard(
+                "k1",
+                &BTreeSet::from([0, 1, 3]),
+                Watermarks {
+                    start: 2,
+                    cleaned: 4,
+                },
+            )
+            .unwrap();
+
+        let offsets = HashMap::from([("k1".to_string(), 0)]);
+        let Payload::PollOk { msgs, .. } = node
+            .poll(offsets, PollLimits::default(), PollFormat::Compact)
+            .unwrap()
+        else {
+            panic!("Expected a poll_ok");
+        };
+        assert_eq!(msgs["k1"], compact(&[[2, 2], [4, 4]]));
+    }
+
+    #[test]
+    fn poll_stops_at_missing_offset() {
+        let mut node = idle_node("n1", vec![]);
+
+        node.storage.insert("k1", 0, &record(10)).unwrap();
+        node.storage.insert("k1", 1, &record(11)).unwrap();
+        node.storage.insert("k1", 3, &record(13)).unwrap();
+
+        let poll = |offset| {
+            let offsets = HashMap::from([("k1".to_string(), offset)]);
+            match node
+                .poll(offsets, PollLimits::default(), PollFormat::Compact)
+                .unwrap()
+            {
+                Payload::PollOk { mut msgs, more } => {
+                    assert!(more.is_empty());
+                    msgs.remove("k1").unwrap()
+                }
+                payload => panic!("Expected a poll_ok, got {payload:?}"),
+            }
+        };
+
+        assert_eq!(poll(0), compact(&[[0, 10], [1, 11]]));
+        assert!(poll(2).is_empty());
+        assert_eq!(poll(3), compact(&[[3, 13]]));
+    }
 }
@@ -0,0 +1,180 @@
+use std::collections::{BTreeSet, HashMap};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::storage::{Storage, Watermarks};
+
+/// How much of a log is kept. Messages are only discarded before the committed offsets of all
+/// consumer groups, and offsets never change, so a consumer skips over discarded messages.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    /// Amount of newest messages to keep.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_messages: Option<usize>,
+    /// Age in milliseconds after which messages are discarded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_age_ms: Option<u64>,
+    /// Keep only the newest message of every compaction key, see [`crate::storage::Record::compaction_key`].
+    #[serde(default)]
+    pub compact: bool,
+}
+
+/// Offsets of the log `key` to discard under `policy` and the watermarks after discarding them,
+/// or `None` when nothing changes. Only offsets before `floor` are discarded.
+pub fn plan(
+    storage: &dyn Storage,
+    key: &str,
+    policy: &RetentionPolicy,
+    floor: usize,
+    now: u64,
+) -> Result<Option<(BTreeSet<usize>, Watermarks)>> {
+    let before = storage.watermarks(key);
+    let offsets = storage.offsets(key);
+    let Some(last) = offsets.last() else {
+        return Ok(None);
+    };
+
+    let mut start = before.start;
+
+    if let Some(max_messages) = policy.max_messages {
+        start = start.max((last + 1).saturating_sub(max_messages));
+    }
+
+    if let Some(max_age) = policy.max_age_ms {
+        let from = start;
+        for offset in offsets.iter().filter(|offset| **offset >= from) {
+            match storage.get(key, *offset)? {
+                Some(record)
+                    if *offset < floor && record.timestamp.saturating_add(max_age) < now =>
+                {
+                    start = offset + 1
+                }
+                _ => break,
+            }
+        }
+    }
+
+    let start = start.min(floor).max(before.start);
+    let mut discard = offsets
+        .iter()
+        .copied()
+        .filter(|offset| *offset < start)
+        .collect::<BTreeSet<_>>();
+    let mut cleaned = before.cleaned.max(start);
+
+    if policy.compact {
+        let mut records = Vec::with_capacity(offsets.len());
+        for offset in offsets.iter().filter(|offset| **offset >= start) {
+            if let Some(record) = storage.get(key, *offset)? {
+                records.push((*offset, record.compaction_key()));
+            }
+        }
+
+        let latest = records
+            .iter()
+            .map(|(offset, compaction_key)| (compaction_key, *offset))
+            .collect::<HashMap<_, _>>();
+
+        discard.extend(
+            records
+                .iter()
+                .filter(|(offset, compaction_key)| {
+                    *offset < floor && latest[compaction_key] != *offset
+                })
+                .map(|(offset, _)| *offset),
+        );
+        cleaned = cleaned.max(floor);
+    }
+
+    let watermarks = Watermarks { start, cleaned };
+    if discard.is_empty() && watermarks == before {
+        return Ok(None);
+    }
+
+    Ok(Some((discard, watermarks)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::storage::{MemoryStorage, Record};
+
+    use super::*;
+
+    fn storage(msgs: &[usize]) -> MemoryStorage {
+        let mut storage = MemoryStorage::default();
+        for (offset, msg) in msgs.iter().enumerate() {
+            let record = Record {
+                msg: (*msg).into(),
+                headers: BTreeMap::new(),
+                timestamp: offset as u64 * 1000,
+                producer: None,
+                tombstone: false,
+            };
+            storage.insert("k1", offset, &record).unwrap();
+        }
+        storage
+    }
+
+    #[test]
+    fn truncates_up_to_floor() {
+        let storage = storage(&[0, 1, 2, 3, 4, 5]);
+        let policy = RetentionPolicy {
+            max_messages: Some(2),
+            ..Default::default()
+        };
+
+        let (discard, watermarks) = plan(&storage, "k1", &policy, 3, 0).unwrap().unwrap();
+        assert_eq!(discard, BTreeSet::from([0, 1, 2]));
+        assert_eq!(watermarks.start, 3);
+
+        assert!(plan(&storage, "k1", &policy, 0, 0).unwrap().is_none());
+    }
+
+    #[test]
+    fn discards_by_age() {
+        let storage = storage(&[0, 1, 2, 3]);
+        let policy = RetentionPolicy {
+            max_age_ms: Some(1500),
+            ..Default::default()
+        };
+
+        let (discard, watermarks) = plan(&storage, "k1", &policy, 4, 3000).unwrap().unwrap();
+        assert_eq!(discard, BTreeSet::from([0, 1]));
+        assert_eq!(watermarks.start, 2);
+    }
+
+    #[test]
+    fn huge_age_keeps_messages() {
+        let storage = storage(&[0, 1, 2, 3]);
+        let policy = RetentionPolicy {
+            max_age_ms: Some(u64::MAX),
+            ..Default::default()
+        };
+
+        assert!(plan(&storage, "k1", &policy, 4, u64::MAX)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn compacts_superseded_values() {
+        let storage = storage(&[7, 8, 7, 9, 8]);
+        let policy = RetentionPolicy {
+            compact: true,
+            ..Default::default()
+        };
+
+        let (discard, watermarks) = plan(&storage, "k1", &policy, 2, 0).unwrap().unwrap();
+        assert_eq!(discard, BTreeSet::from([0, 1]));
+        assert_eq!(
+            watermarks,
+            Watermarks {
+                start: 0,
+                cleaned: 2
+            }
+        );
+    }
+}
@@ -0,0 +1,639 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::Debug,
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Write},
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Size after which a new segment file is started for a log.
+const SEGMENT_BYTES: u64 = 1024 * 1024;
+
+/// Length of the frame header, the payload length, checksum and offset.
+const HEADER_BYTES: usize = 4 + 4 + 8;
+
+/// File in the directory of a log holding its watermarks.
+const WATERMARKS_FILE: &str = "watermarks.json";
+
+/// Message stored in a log.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub msg: Value,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    /// Milliseconds since the Unix epoch at which the message was appended.
+    pub timestamp: u64,
+    #[serde(flatten)]
+    pub producer: Option<ProducerSeq>,
+    /// Fills an offset which was claimed but never appended to, skipped by polls.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub tombstone: bool,
+}
+
+/// Send of an idempotent producer, a retry of the send carries the same sequence number.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
+pub struct ProducerSeq {
+    pub producer_id: String,
+    pub seq: u64,
+}
+
+impl Record {
+    pub fn tombstone(timestamp: u64) -> Self {
+        Self {
+            msg: Value::Null,
+            headers: BTreeMap::new(),
+            timestamp,
+            producer: None,
+            tombstone: true,
+        }
+    }
+
+    /// Key under which compaction keeps only the newest message, the `key` header if set or the
+    /// message otherwise.
+    pub fn compaction_key(&self) -> String {
+        self.headers
+            .get("key")
+            .cloned()
+            .unwrap_or_else(|| self.msg.to_string())
+    }
+}
+
+/// Bounds of the part of a log which is discarded by retention.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Watermarks {
+    /// First offset which is kept, all offsets before it are discarded.
+    pub start: usize,
+    /// Offset up to which the log is compacted. Missing offsets before it are discarded, instead
+    /// of not yet replicated.
+    pub cleaned: usize,
+}
+
+/// Where the messages of the logs are kept.
+pub trait Storage: Debug {
+    /// Stores `record` at `offset` of the log `key`. Records are immutable, storing an offset a
+    /// second time is ignored.
+    fn insert(&mut self, key: &str, offset: usize, record: &Record) -> Result<()>;
+
+    /// The record at `offset` of the log `key`, if stored.
+    fn get(&self, key: &str, offset: usize) -> Result<Option<Record>>;
+
+    /// Stored offsets of the log `key`, in order.
+    fn offsets(&self, key: &str) -> Vec<usize>;
+
+    fn watermarks(&self, key: &str) -> Watermarks;
+
+    /// Removes `offsets` from the log `key`, and moves its watermarks to `watermarks`.
+    fn discard(
+        &mut self,
+        key: &str,
+        offsets: &BTreeSet<usize>,
+        watermarks: Watermarks,
+    ) -> Result<()>;
+}
+
+#[derive(Debug, Default)]
+struct MemoryLog {
+    records: BTreeMap<usize, Record>,
+    watermarks: Watermarks,
+}
+
+/// Logs kept in memory, lost when the node stops.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    logs: HashMap<String, MemoryLog>,
+}
+
+impl Storage for MemoryStorage {
+    fn insert(&mut self, key: &str, offset: usize, record: &Record) -> Result<()> {
+        self.logs
+            .entry(key.to_string())
+            .or_default()
+            .records
+            .entry(offset)
+            .or_insert_with(|| record.clone());
+
+        Ok(())
+    }
+
+    fn get(&self, key: &str, offset: usize) -> Result<Option<Record>> {
+        Ok(self
+            .logs
+            .get(key)
+            .and_then(|log| log.records.get(&offset))
+            .cloned())
+    }
+
+    fn offsets(&self, key: &str) -> Vec<usize> {
+        self.logs
+            .get(key)
+            .map(|log| log.records.keys().copied().collect())
+            .unwrap_or_default()
+    }
+
+    fn watermarks(&self, key: &str) -> Watermarks {
+        self.logs
+            .get(key)
+            .map(|log| log.watermarks)
+            .unwrap_or_default()
+    }
+
+    fn discard(
+        &mut self,
+        key: &str,
+        offsets: &BTreeSet<usize>,
+        watermarks: Watermarks,
+    ) -> Result<()> {
+        let log = self.logs.entry(key.to_string()).or_default();
+
+        log.records.retain(|offset, _| !offsets.contains(offset));
+        log.watermarks = watermarks;
+
+        Ok(())
+    }
+}
+
+/// Append-only file holding a part of a log, as a sequence of frames:
+///
+/// | length (u32) | crc32 of offset and payload (u32) | offset (u64) | payload (JSON) |
+///
+/// All integers are little endian.
+#[derive(Debug)]
+struct Segment {
+    file: File,
+    len: u64,
+}
+
+/// Log of a single key, split into numbered segments of which only the last is written to.
+#[derive(Debug)]
+struct DiskLog {
+    dir: PathBuf,
+    segments: BTreeMap<usize, Segment>,
+    /// Segment and position of the frame of every stored offset.
+    index: BTreeMap<usize, (usize, u64)>,
+    watermarks: Watermarks,
+}
+
+/// Logs kept in segment files, one directory per key. The offset index is rebuild from the
+/// segments when opened, truncating frames which were not completely written.
+#[derive(Debug)]
+pub struct DiskStorage {
+    dir: PathBuf,
+    logs: HashMap<String, DiskLog>,
+}
+
+impl DiskStorage {
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir).with_context(|| format!("Failed to create {dir:?}"))?;
+
+        let mut logs = HashMap::new();
+
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let Some(key) = entry.file_name().to_str().and_then(decode_key) else {
+                continue;
+            };
+
+            logs.insert(key, DiskLog::open(entry.path())?);
+        }
+
+        Ok(Self { dir, logs })
+    }
+
+    fn log(&mut self, key: &str) -> Result<&mut DiskLog> {
+        if !self.logs.contains_key(key) {
+            let log = DiskLog::open(self.dir.join(encode_key(key)))?;
+            self.logs.insert(key.to_string(), log);
+        }
+
+        Ok(self.logs.get_mut(key).expect("Log is just opened"))
+    }
+}
+
+impl Storage for DiskStorage {
+    fn insert(&mut self, key: &str, offset: usize, record: &Record) -> Result<()> {
+        let log = self.log(key)?;
+        if log.index.contains_key(&offset) {
+            return Ok(());
+        }
+
+        log.append(offset, &serde_json::to_vec(record)?)
+    }
+
+    fn get(&self, key: &str, offset: usize) -> Result<Option<Record>> {
+        let Some(log) = self.logs.get(key) else {
+            return Ok(None);
+        };
+
+        log.read(offset)?
+            .map(|payload| Ok(serde_json::from_slice(&payload)?))
+            .transpose()
+    }
+
+    fn offsets(&self, key: &str) -> Vec<usize> {
+        self.logs
+            .get(key)
+            .map(|log| log.index.keys().copied().collect())
+            .unwrap_or_default()
+    }
+
+    fn watermarks(&self, key: &str) -> Watermarks {
+        self.logs
+            .get(key)
+            .map(|log| log.watermarks)
+            .unwrap_or_default()
+    }
+
+    fn discard(
+        &mut self,
+        key: &str,
+        offsets: &BTreeSet<usize>,
+        watermarks: Watermarks,
+    ) -> Result<()> {
+        let log = self.log(key)?;
+        log.set_watermarks(watermarks)?;
+
+        let segments = offsets
+            .iter()
+            .filter_map(|offset| log.index.get(offset).map(|(number, _)| *number))
+            .collect::<BTreeSet<_>>();
+
+        for number in segments {
+            log.rewrite(number, offsets)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl DiskLog {
+    fn open(dir: PathBuf) -> Result<Self> {
+        fs::create_dir_all(&dir).with_context(|| format!("Failed to create {dir:?}"))?;
+
+        let watermarks = match fs::read(dir.join(WATERMARKS_FILE)) {
+            Ok(json) => serde_json::from_slice(&json)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => Watermarks::default(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut log = Self {
+            dir,
+            segments: BTreeMap::new(),
+            index: BTreeMap::new(),
+            watermarks,
+        };
+
+        let mut numbers = fs::read_dir(&log.dir)?
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                (path.extension()? == "log").then_some(())?;
+                path.file_stem()?.to_str()?.parse::<usize>().ok()
+            })
+            .collect::<Vec<_>>();
+        numbers.sort();
+
+        for number in numbers {
+            log.recover(number)?;
+        }
+
+        Ok(log)
+    }
+
+    fn segment_path(&self, number: usize) -> PathBuf {
+        self.dir.join(format!("{number:020}.log"))
+    }
+
+    /// Opens a segment, adding its frames to the index. The segment is truncated at the first
+    /// frame which is incomplete or does not match its checksum, which is left by a write that
+    /// was interrupted.
+    fn recover(&mut self, number: usize) -> Result<()> {
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(self.segment_path(number))?;
+        let size = file.metadata()?.len();
+
+        let mut position = 0;
+        while let Some((offset, _, frame_len)) = read_frame(&file, position, size)? {
+            self.index.insert(offset, (number, position));
+            position += frame_len;
+        }
+
+        if position < size {
+            file.set_len(position)?;
+        }
+
+        self.segments.insert(
+            number,
+            Segment {
+                file,
+                len: position,
+            },
+        );
+
+        Ok(())
+    }
+
+    fn append(&mut self, offset: usize, payload: &[u8]) -> Result<()> {
+        let number = match self.segments.last_key_value() {
+            Some((number, segment)) if segment.len < SEGMENT_BYTES => *number,
+            Some((number, _)) => number + 1,
+            None => 0,
+        };
+
+        if !self.segments.contains_key(&number) {
+            self.recover(number)?;
+        }
+
+        let segment = self
+            .segments
+            .get_mut(&number)
+            .expect("Segment is just opened");
+        let frame = encode_frame(offset, payload)?;
+
+        segment.file.write_all(&frame)?;
+        segment.file.sync_data()?;
+
+        self.index.insert(offset, (number, segment.len));
+        segment.len += frame.len() as u64;
+
+        Ok(())
+    }
+
+    fn read(&self, offset: usize) -> Result<Option<Vec<u8>>> {
+        let Some((number, position)) = self.index.get(&offset) else {
+            return Ok(None);
+        };
+
+        let Some(segment) = self.segments.get(number) else {
+            bail!("Index of offset {offset} points to missing segment {number}");
+        };
+
+        match read_frame(&segment.file, *position, segment.len)? {
+            Some((stored, payload, _)) if stored == offset => Ok(Some(payload)),
+            _ => bail!("Index of offset {offset} points to an invalid frame"),
+        }
+    }
+
+    /// Writes the watermarks next to the segments, through a temporary file so a crash does not
+    /// leave them half written.
+    fn set_watermarks(&mut self, watermarks: Watermarks) -> Result<()> {
+        let path = self.dir.join(WATERMARKS_FILE);
+        let temp = path.with_extension("tmp");
+
+        fs::write(&temp, serde_json::to_vec(&watermarks)?)?;
+        File::open(&temp)?.sync_all()?;
+        fs::rename(temp, path)?;
+
+        self.watermarks = watermarks;
+        Ok(())
+    }
+
+    /// Copies a segment without the frames of `discarded`, replacing the original. Segments left
+    /// empty are removed, unless it is the segment written to.
+    fn rewrite(&mut self, number: usize, discarded: &BTreeSet<usize>) -> Result<()> {
+        let Some(segment) = self.segments.remove(&number) else {
+            return Ok(());
+        };
+
+        let path = self.segment_path(number);
+        let temp = path.with_extension("tmp");
+        let mut kept = File::create(&temp)?;
+
+        let mut position = 0;
+        while let Some((offset, payload, frame_len)) =
+            read_frame(&segment.file, position, segment.len)?
+        {
+            if !discarded.contains(&offset) {
+                kept.write_all(&encode_frame(offset, &payload)?)?;
+            }
+            position += frame_len;
+        }
+
+        kept.sync_all()?;
+        let empty = kept.metadata()?.len() == 0;
+        fs::rename(&temp, &path)?;
+
+        self.index.retain(|_, (segment, _)| *segment != number);
+
+        if empty
+            && self
+                .segments
+                .last_key_value()
+                .is_some_and(|(last, _)| *last > number)
+        {
+            fs::remove_file(path)?;
+            Ok(())
+        } else {
+            self.recover(number)
+        }
+    }
+}
+
+fn encode_frame(offset: usize, payload: &[u8]) -> Result<Vec<u8>> {
+    let len = u32::try_from(payload.len()).context("Message is too large to store")?;
+    let offset = (offset as u64).to_le_bytes();
+
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&offset);
+    hasher.update(payload);
+
+    let mut frame = Vec::with_capacity(HEADER_BYTES + payload.len());
+    frame.extend_from_slice(&len.to_le_bytes());
+    frame.extend_from_slice(&hasher.finalize().to_le_bytes());
+    frame.extend_from_slice(&offset);
+    frame.extend_from_slice(payload);
+
+    Ok(frame)
+}
+
+/// Reads the frame at `position`, returning its offset, payload and total length. Returns `None`
+/// at `end` or for a frame which is incomplete or corrupt.
+fn read_frame(file: &File, position: u64, end: u64) -> Result<Option<(usize, Vec<u8>, u64)>> {
+    let mut header = [0; HEADER_BYTES];
+    match file.read_exact_at(&mut header, position) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    let len = u32::from_le_bytes(header[0..4].try_into()?) as usize;
+    let crc = u32::from_le_bytes(header[4..8].try_into()?);
+    let offset = &header[8..16];
+
+    if position + (HEADER_BYTES + len) as u64 > end {
+        return Ok(None);
+    }
+
+    let mut payload = vec![0; len];
+    match file.read_exact_at(&mut payload, position + HEADER_BYTES as u64) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(offset);
+    hasher.update(&payload);
+    if hasher.finalize() != crc {
+        return Ok(None);
+    }
+
+    let offset = u64::from_le_bytes(offset.try_into()?) as usize;
+    Ok(Some((offset, payload, (HEADER_BYTES + len) as u64)))
+}
+
+/// Directory name for the log of `key`, hex encoded so any key is a valid file name. The prefix
+/// keeps the name of the empty key from being empty.
+fn encode_key(key: &str) -> String {
+    let hex = key
+        .bytes()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<String>();
+
+    format!("k{hex}")
+}
+
+fn decode_key(name: &str) -> Option<String> {
+    let name = name.strip_prefix('k')?;
+    let bytes = (0..name.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(name.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<_>>>()?;
+
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("kafka-storage-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn record(msg: usize) -> Record {
+        Record {
+            msg: msg.into(),
+            headers: BTreeMap::new(),
+            timestamp: 0,
+            producer: None,
+            tombstone: false,
+        }
+    }
+
+    #[test]
+    fn keys_round_trip_through_file_names() {
+        for key in ["", "k1", "../a b", "ключ"] {
+            let name = encode_key(key);
+            assert!(!name.is_empty());
+            assert_eq!(decode_key(&name).as_deref(), Some(key));
+        }
+
+        assert_eq!(decode_key("6b31"), None);
+    }
+
+    #[test]
+    fn memory_keeps_first_message() {
+        let mut storage = MemoryStorage::default();
+
+        storage.insert("k1", 0, &record(10)).unwrap();
+        storage.insert("k1", 0, &record(11)).unwrap();
+
+        assert_eq!(storage.get("k1", 0).unwrap(), Some(record(10)));
+        assert_eq!(storage.get("k1", 1).unwrap(), None);
+        assert_eq!(storage.get("k2", 0).unwrap(), None);
+    }
+
+    #[test]
+    fn disk_survives_reopen() {
+        let dir = temp_dir("reopen");
+
+        {
+            let mut storage = DiskStorage::open(&dir).unwrap();
+            storage.insert("k/1", 1, &record(11)).unwrap();
+            storage.insert("k/1", 0, &record(10)).unwrap();
+            storage.insert("k2", 0, &record(20)).unwrap();
+        }
+
+        let storage = DiskStorage::open(&dir).unwrap();
+        assert_eq!(storage.get("k/1", 0).unwrap(), Some(record(10)));
+        assert_eq!(storage.get("k/1", 1).unwrap(), Some(record(11)));
+        assert_eq!(storage.get("k2", 0).unwrap(), Some(record(20)));
+        assert_eq!(storage.get("k2", 1).unwrap(), None);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn disk_truncates_torn_write() {
+        let dir = temp_dir("torn");
+
+        {
+            let mut storage = DiskStorage::open(&dir).unwrap();
+            storage.insert("k1", 0, &record(10)).unwrap();
+            storage.insert("k1", 1, &record(11)).unwrap();
+        }
+
+        let segment = dir.join(encode_key("k1")).join(format!("{:020}.log", 0));
+        let len = fs::metadata(&segment).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&segment)
+            .unwrap()
+            .set_len(len - 1)
+            .unwrap();
+
+        let mut storage = DiskStorage::open(&dir).unwrap();
+        assert_eq!(storage.get("k1", 0).unwrap(), Some(record(10)));
+        assert_eq!(storage.get("k1", 1).unwrap(), None);
+
+        storage.insert("k1", 1, &record(12)).unwrap();
+        drop(storage);
+
+        let storage = DiskStorage::open(&dir).unwrap();
+        assert_eq!(storage.get("k1", 1).unwrap(), Some(record(12)));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn disk_discards_records() {
+        let dir = temp_dir("discard");
+        let watermarks = Watermarks {
+            start: 2,
+            cleaned: 4,
+        };
+
+        {
+            let mut storage = DiskStorage::open(&dir).unwrap();
+            for offset in 0..5 {
+                storage.insert("k1", offset, &record(offset)).unwrap();
+            }
+
+            storage
+                .discard("k1", &BTreeSet::from([0, 1, 3]), watermarks)
+                .unwrap();
+            assert_eq!(storage.offsets("k1"), vec![2, 4]);
+
+            storage.insert("k1", 5, &record(5)).unwrap();
+        }
+
+        let storage = DiskStorage::open(&dir).unwrap();
+        assert_eq!(storage.offsets("k1"), vec![2, 4, 5]);
+        assert_eq!(storage.get("k1", 4).unwrap(), Some(record(4)));
+        assert_eq!(storage.watermarks("k1"), watermarks);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use dist_sys::raft::{RaftNode, RaftPayload, StateMachine};
+use serde::{Deserialize, Serialize};
+
+/// Error code of Maelstrom for a request which definitely did not happen.
+const TEMPORARILY_UNAVAILABLE: usize = 11;
+const KEY_DOES_NOT_EXIST: usize = 20;
+const PRECONDITION_FAILED: usize = 22;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case", tag = "type")]
+enum Payload {
+    Read {
+        key: usize,
+    },
+    ReadOk {
+        value: usize,
+    },
+    Write {
+        key: usize,
+        value: usize,
+    },
+    WriteOk,
+    Cas {
+        key: usize,
+        from: usize,
+        to: usize,
+        #[serde(default)]
+        create_if_not_exists: bool,
+    },
+    CasOk,
+    Error {
+        code: usize,
+        text: String,
+    },
+}
+
+#[derive(Debug, Default)]
+struct KvMachine {
+    values: HashMap<usize, usize>,
+}
+
+impl StateMachine for KvMachine {
+    type Payload = Payload;
+
+    fn apply(&mut self, request: Payload) -> Payload {
+        match request {
+            Payload::Read { key } => match self.values.get(&key) {
+                Some(value) => Payload::ReadOk { value: *value },
+                None => Payload::Error {
+                    code: KEY_DOES_NOT_EXIST,
+                    text: format!("Key {key} does not exist"),
+                },
+            },
+            Payload::Write { key, value } => {
+                self.values.insert(key, value);
+                Payload::WriteOk
+            }
+            Payload::Cas {
+                key,
+                from,
+                to,
+                create_if_not_exists,
+            } => match self.values.get(&key) {
+                Some(value) if *value == from => {
+                    self.values.insert(key, to);
+                    Payload::CasOk
+                }
+                Some(value) => Payload::Error {
+                    code: PRECONDITION_FAILED,
+                    text: format!("Expected {from}, but had {value}"),
+                },
+                None if create_if_not_exists => {
+                    self.values.insert(key, to);
+                    Payload::CasOk
+                }
+                None => Payload::Error {
+                    code: KEY_DOES_NOT_EXIST,
+                    text: format!("Key {key} does not exist"),
+                },
+            },
+            m => Payload::Error {
+                code: 10,
+                text: format!("Invalid message for node: {m:?}"),
+            },
+        }
+    }
+
+    fn unavailable() -> Payload {
+        Payload::Error {
+            code: TEMPORARILY_UNAVAILABLE,
+            text: "No leader available".to_string(),
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    dist_sys::run_dist_sys::<RaftNode<KvMachine>, RaftPayload<Payload>>()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::mpsc,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use dist_sys::{Body, Message, Node};
+
+    use super::*;
+
+    #[test]
+    fn applies_requests_in_order() {
+        let mut machine = KvMachine::default();
+
+        assert!(matches!(
+            machine.apply(Payload::Read { key: 1 }),
+            Payload::Error {
+                code: KEY_DOES_NOT_EXIST,
+                ..
+            }
+        ));
+        assert_eq!(
+            machine.apply(Payload::Write { key: 1, value: 2 }),
+            Payload::WriteOk
+        );
+        assert!(matches!(
+            machine.apply(Payload::Cas {
+                key: 1,
+                from: 3,
+                to: 4,
+                create_if_not_exists: false
+            }),
+            Payload::Error {
+                code: PRECONDITION_FAILED,
+                ..
+            }
+        ));
+        assert_eq!(
+            machine.apply(Payload::Cas {
+                key: 1,
+                from: 2,
+                to: 4,
+                create_if_not_exists: false
+            }),
+            Payload::CasOk
+        );
+        assert_eq!(
+            machine.apply(Payload::Read { key: 1 }),
+            Payload::ReadOk { value: 4 }
+        );
+    }
+
+    #[test]
+    fn single_node_serves_requests_once_leader() {
+        let (in_tx, in_rx) = mpsc::channel();
+        let (out_tx, out_rx) = mpsc::channel();
+
+        thread::spawn(move || {
+            let mut node = RaftNode::<KvMachine>::initialize(
+                out_tx,
+                in_rx,
+                "n1".to_string(),
+                vec!["n1".to_string()],
+            );
+            node.run().unwrap();
+        });
+
+        let request = |msg_id, payload| {
+            in_tx
+                .send(Message {
+                    src: "c1".to_string(),
+                    dest: "n1".to_string(),
+                    body: Body {
+                        msg_id: Some(msg_id),
+                        in_reply_to: None,
+                        payload: RaftPayload::Workload(payload),
+                    },
+                })
+                .unwrap();
+
+            let reply = out_rx
+                .recv_timeout(Duration::from_millis(500))
+                .expect("Failed to get a response in a reasonable time");
+            assert_eq!(reply.body.in_reply_to, Some(msg_id));
+            reply.body.payload
+        };
+
+        // Requests are refused until the node elected itself.
+        let started = Instant::now();
+        let mut msg_id = 0;
+        loop {
+            msg_id += 1;
+            match request(msg_id, Payload::Write { key: 1, value: 2 }) {
+                RaftPayload::Workload(Payload::WriteOk) => break,
+                RaftPayload::Workload(Payload::Error {
+                    code: TEMPORARILY_UNAVAILABLE,
+                    ..
+                }) if started.elapsed() < Duration::from_secs(3) => {
+                    thread::sleep(Duration::from_millis(50));
+                }
+                payload => panic!("Unexpected reply: {payload:?}"),
+            }
+        }
+
+        assert_eq!(
+            request(msg_id + 1, Payload::Read { key: 1 }),
+            RaftPayload::Workload(Payload::ReadOk { value: 2 })
+        );
+    }
+}
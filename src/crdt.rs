@@ -0,0 +1,566 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Debug,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// State-based conflict-free replicated data type. Replicas converge by merging each others state,
+/// in any order and any amount of times.
+pub trait Crdt: Clone + Default + PartialEq + Debug + Serialize + DeserializeOwned {
+    /// Merges the state of another replica into this one.
+    fn merge(&mut self, other: &Self);
+
+    /// The part of this state which is not yet contained in `other`. Merging the delta into
+    /// `other` has the same result as merging the complete state, and the delta is empty (equal to
+    /// the default) when `other` already contains everything.
+    fn delta(&self, other: &Self) -> Self;
+}
+
+/// Grow-only counter, each replica only increments its own entry.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct GCounter {
+    counts: BTreeMap<String, u64>,
+}
+
+impl GCounter {
+    pub fn increment(&mut self, replica: &str, amount: u64) {
+        *self.counts.entry(replica.to_string()).or_default() += amount;
+    }
+
+    /// The count of a single replica.
+    pub fn get(&self, replica: &str) -> u64 {
+        self.counts.get(replica).copied().unwrap_or_default()
+    }
+
+    pub fn value(&self) -> u64 {
+        self.counts.values().sum()
+    }
+}
+
+impl Crdt for GCounter {
+    fn merge(&mut self, other: &Self) {
+        for (replica, count) in &other.counts {
+            let entry = self.counts.entry(replica.clone()).or_default();
+            *entry = (*entry).max(*count);
+        }
+    }
+
+    fn delta(&self, other: &Self) -> Self {
+        let counts = self
+            .counts
+            .iter()
+            .filter(|(replica, count)| **count > other.get(replica))
+            .map(|(replica, count)| (replica.clone(), *count))
+            .collect();
+
+        Self { counts }
+    }
+}
+
+/// Counter which can also decrement, kept as a pair of grow-only counters.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct PnCounter {
+    increments: GCounter,
+    decrements: GCounter,
+}
+
+impl PnCounter {
+    pub fn add(&mut self, replica: &str, delta: i64) {
+        if delta >= 0 {
+            self.increments.increment(replica, delta.unsigned_abs());
+        } else {
+            self.decrements.increment(replica, delta.unsigned_abs());
+        }
+    }
+
+    pub fn value(&self) -> i64 {
+        self.increments.value() as i64 - self.decrements.value() as i64
+    }
+}
+
+impl Crdt for PnCounter {
+    fn merge(&mut self, other: &Self) {
+        self.increments.merge(&other.increments);
+        self.decrements.merge(&other.decrements);
+    }
+
+    fn delta(&self, other: &Self) -> Self {
+        Self {
+            increments: self.increments.delta(&other.increments),
+            decrements: self.decrements.delta(&other.decrements),
+        }
+    }
+}
+
+/// Grow-only set.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct GSet<T: Ord> {
+    elements: BTreeSet<T>,
+}
+
+impl<T: Ord> Default for GSet<T> {
+    fn default() -> Self {
+        Self {
+            elements: BTreeSet::new(),
+        }
+    }
+}
+
+impl<T: Ord> GSet<T> {
+    /// Adds an element, returning whether it was not yet in the set.
+    pub fn insert(&mut self, element: T) -> bool {
+        self.elements.insert(element)
+    }
+
+    pub fn contains(&self, element: &T) -> bool {
+        self.elements.contains(element)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.elements.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.elements.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.elements.is_empty()
+    }
+}
+
+impl<T> Crdt for GSet<T>
+where
+    T: Ord + Clone + Debug + Serialize + DeserializeOwned,
+{
+    fn merge(&mut self, other: &Self) {
+        self.elements.extend(other.elements.iter().cloned());
+    }
+
+    fn delta(&self, other: &Self) -> Self {
+        Self {
+            elements: self.elements.difference(&other.elements).cloned().collect(),
+        }
+    }
+}
+
+/// Set where elements can be removed once, a removed element can not be added again.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(bound = "T: Ord + Serialize + DeserializeOwned")]
+pub struct TwoPSet<T: Ord> {
+    added: GSet<T>,
+    removed: GSet<T>,
+}
+
+impl<T: Ord> Default for TwoPSet<T> {
+    fn default() -> Self {
+        Self {
+            added: GSet::default(),
+            removed: GSet::default(),
+        }
+    }
+}
+
+impl<T: Ord + Clone> TwoPSet<T> {
+    pub fn insert(&mut self, element: T) {
+        self.added.insert(element);
+    }
+
+    pub fn remove(&mut self, element: T) {
+        self.removed.insert(element);
+    }
+
+    pub fn contains(&self, element: &T) -> bool {
+        self.added.contains(element) && !self.removed.contains(element)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.added
+            .iter()
+            .filter(|element| !self.removed.contains(element))
+    }
+}
+
+impl<T> Crdt for TwoPSet<T>
+where
+    T: Ord + Clone + Debug + Serialize + DeserializeOwned,
+{
+    fn merge(&mut self, other: &Self) {
+        self.added.merge(&other.added);
+        self.removed.merge(&other.removed);
+    }
+
+    fn delta(&self, other: &Self) -> Self {
+        Self {
+            added: self.added.delta(&other.added),
+            removed: self.removed.delta(&other.removed),
+        }
+    }
+}
+
+/// Unique tag of a single add, the counter increasing per replica.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Dot {
+    pub replica: String,
+    pub counter: u64,
+}
+
+/// Observed-remove set, a remove only affects the adds which were observed by the removing
+/// replica, so concurrent adds win.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(bound = "T: Ord + Serialize + DeserializeOwned")]
+pub struct OrSet<T: Ord> {
+    entries: BTreeSet<(T, Dot)>,
+    tombstones: BTreeSet<Dot>,
+    clock: GCounter,
+}
+
+impl<T: Ord> Default for OrSet<T> {
+    fn default() -> Self {
+        Self {
+            entries: BTreeSet::new(),
+            tombstones: BTreeSet::new(),
+            clock: GCounter::default(),
+        }
+    }
+}
+
+impl<T: Ord + Clone> OrSet<T> {
+    pub fn insert(&mut self, replica: &str, element: T) {
+        self.clock.increment(replica, 1);
+
+        let dot = Dot {
+            replica: replica.to_string(),
+            counter: self.clock.get(replica),
+        };
+        self.entries.insert((element, dot));
+    }
+
+    /// Removes all observed adds of the element.
+    pub fn remove(&mut self, element: &T) {
+        let observed = self
+            .entries
+            .iter()
+            .filter(|(entry, _)| entry == element)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for entry in observed {
+            self.entries.remove(&entry);
+            self.tombstones.insert(entry.1);
+        }
+    }
+
+    pub fn contains(&self, element: &T) -> bool {
+        self.entries.iter().any(|(entry, _)| entry == element)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        let mut elements = self
+            .entries
+            .iter()
+            .map(|(element, _)| element)
+            .collect::<Vec<_>>();
+        elements.dedup();
+
+        elements.into_iter()
+    }
+}
+
+impl<T> Crdt for OrSet<T>
+where
+    T: Ord + Clone + Debug + Serialize + DeserializeOwned,
+{
+    fn merge(&mut self, other: &Self) {
+        self.tombstones.extend(other.tombstones.iter().cloned());
+        self.entries.extend(other.entries.iter().cloned());
+        self.entries
+            .retain(|(_, dot)| !self.tombstones.contains(dot));
+        self.clock.merge(&other.clock);
+    }
+
+    fn delta(&self, other: &Self) -> Self {
+        Self {
+            entries: self
+                .entries
+                .iter()
+                .filter(|(_, dot)| !other.tombstones.contains(dot))
+                .filter(|entry| !other.entries.contains(entry))
+                .cloned()
+                .collect(),
+            tombstones: self
+                .tombstones
+                .difference(&other.tombstones)
+                .cloned()
+                .collect(),
+            clock: self.clock.delta(&other.clock),
+        }
+    }
+}
+
+/// Time of a write, ties between replicas are broken by the replica id.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp {
+    pub time: u64,
+    pub replica: String,
+}
+
+/// Register where the write with the highest timestamp wins.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct LwwRegister<T> {
+    entry: Option<(Timestamp, T)>,
+}
+
+impl<T> Default for LwwRegister<T> {
+    fn default() -> Self {
+        Self { entry: None }
+    }
+}
+
+impl<T> LwwRegister<T> {
+    /// Writes a value, timestamped with the wall clock or just after the current write when the
+    /// clock lags behind.
+    pub fn set(&mut self, replica: &str, value: T) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis() as u64)
+            .unwrap_or_default();
+        let time = match &self.entry {
+            Some((timestamp, _)) => now.max(timestamp.time + 1),
+            None => now,
+        };
+
+        let timestamp = Timestamp {
+            time,
+            replica: replica.to_string(),
+        };
+        self.entry = Some((timestamp, value));
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        self.entry.as_ref().map(|(_, value)| value)
+    }
+
+    pub fn timestamp(&self) -> Option<&Timestamp> {
+        self.entry.as_ref().map(|(timestamp, _)| timestamp)
+    }
+}
+
+impl<T> Crdt for LwwRegister<T>
+where
+    T: Clone + PartialEq + Debug + Serialize + DeserializeOwned,
+{
+    fn merge(&mut self, other: &Self) {
+        if other.timestamp() > self.timestamp() {
+            self.entry = other.entry.clone();
+        }
+    }
+
+    fn delta(&self, other: &Self) -> Self {
+        if self.timestamp() > other.timestamp() {
+            self.clone()
+        } else {
+            Self::default()
+        }
+    }
+}
+
+/// Map of last-writer-wins registers, removed keys are kept as a timestamped tombstone.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(bound = "K: Ord + Serialize + DeserializeOwned, V: Serialize + DeserializeOwned")]
+pub struct LwwMap<K: Ord, V> {
+    entries: BTreeMap<K, LwwRegister<Option<V>>>,
+}
+
+impl<K: Ord, V> Default for LwwMap<K, V> {
+    fn default() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+        }
+    }
+}
+
+impl<K: Ord, V> LwwMap<K, V> {
+    pub fn insert(&mut self, replica: &str, key: K, value: V) {
+        self.entries
+            .entry(key)
+            .or_default()
+            .set(replica, Some(value));
+    }
+
+    pub fn remove(&mut self, replica: &str, key: K) {
+        self.entries.entry(key).or_default().set(replica, None);
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.entries.get(key)?.get()?.as_ref()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.entries
+            .iter()
+            .filter_map(|(key, register)| Some((key, register.get()?.as_ref()?)))
+    }
+}
+
+impl<K, V> Crdt for LwwMap<K, V>
+where
+    K: Ord + Clone + Debug + Serialize + DeserializeOwned,
+    V: Clone + PartialEq + Debug + Serialize + DeserializeOwned,
+{
+    fn merge(&mut self, other: &Self) {
+        for (key, register) in &other.entries {
+            self.entries.entry(key.clone()).or_default().merge(register);
+        }
+    }
+
+    fn delta(&self, other: &Self) -> Self {
+        let empty = LwwRegister::default();
+
+        let entries = self
+            .entries
+            .iter()
+            .map(|(key, register)| {
+                (
+                    key,
+                    register.delta(other.entries.get(key).unwrap_or(&empty)),
+                )
+            })
+            .filter(|(_, delta)| delta.entry.is_some())
+            .map(|(key, delta)| (key.clone(), delta))
+            .collect();
+
+        Self { entries }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks that merging converges regardless of order, and that the delta carries everything
+    /// the other replica is missing.
+    fn assert_converges<C: Crdt>(left: C, right: C) {
+        let mut left_right = left.clone();
+        left_right.merge(&right);
+
+        let mut right_left = right.clone();
+        right_left.merge(&left);
+        assert_eq!(left_right, right_left);
+
+        let mut twice = left_right.clone();
+        twice.merge(&right);
+        assert_eq!(twice, left_right);
+
+        let mut from_delta = left.clone();
+        from_delta.merge(&right.delta(&left));
+        assert_eq!(from_delta, left_right);
+
+        assert_eq!(left_right.delta(&left_right), C::default());
+    }
+
+    #[test]
+    fn counters_converge() {
+        let mut left = PnCounter::default();
+        left.add("n1", 5);
+        left.add("n2", -2);
+
+        let mut right = PnCounter::default();
+        right.add("n2", -4);
+        right.add("n3", 1);
+
+        let mut merged = left.clone();
+        merged.merge(&right);
+        assert_eq!(merged.value(), 2);
+
+        assert_converges(left, right);
+    }
+
+    #[test]
+    fn sets_converge() {
+        let mut left = GSet::default();
+        left.insert(1);
+        left.insert(2);
+
+        let mut right = GSet::default();
+        right.insert(2);
+        right.insert(3);
+
+        assert_converges(left, right);
+
+        let mut left = TwoPSet::default();
+        left.insert(1);
+        left.insert(2);
+
+        let mut right = left.clone();
+        right.remove(1);
+        left.insert(3);
+
+        let mut merged = left.clone();
+        merged.merge(&right);
+        assert_eq!(merged.iter().collect::<Vec<_>>(), vec![&2, &3]);
+
+        assert_converges(left, right);
+    }
+
+    #[test]
+    fn or_set_add_wins_over_concurrent_remove() {
+        let mut left = OrSet::default();
+        left.insert("n1", 'a');
+
+        let mut right = left.clone();
+        right.remove(&'a');
+        left.insert("n1", 'a');
+
+        let mut merged = left.clone();
+        merged.merge(&right);
+        assert!(merged.contains(&'a'));
+
+        merged.remove(&'a');
+        let mut stale = left.clone();
+        stale.merge(&merged);
+        assert!(!stale.contains(&'a'));
+
+        assert_converges(left, right);
+    }
+
+    #[test]
+    fn lww_keeps_latest_write() {
+        let mut left = LwwMap::default();
+        left.insert("n1", "a".to_string(), 1);
+        left.insert("n1", "b".to_string(), 2);
+
+        let mut right = left.clone();
+        right.insert("n2", "a".to_string(), 3);
+        right.remove("n2", "b".to_string());
+
+        let mut merged = left.clone();
+        merged.merge(&right);
+        assert_eq!(merged.get(&"a".to_string()), Some(&3));
+        assert_eq!(merged.get(&"b".to_string()), None);
+
+        assert_converges(left, right);
+    }
+
+    #[test]
+    fn serializes_to_json() {
+        let mut set = OrSet::default();
+        set.insert("n1", 4);
+
+        let json = serde_json::to_string(&set).unwrap();
+        assert_eq!(serde_json::from_str::<OrSet<usize>>(&json).unwrap(), set);
+
+        let mut map = LwwMap::default();
+        map.insert("n1", 1, "x".to_string());
+
+        let json = serde_json::to_string(&map).unwrap();
+        assert_eq!(
+            serde_json::from_str::<LwwMap<usize, String>>(&json).unwrap(),
+            map
+        );
+    }
+}
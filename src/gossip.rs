@@ -0,0 +1,384 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt::Debug,
+    sync::mpsc::{self, RecvTimeoutError},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use rand::seq::SliceRandom;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{crdt::Crdt, Body, Message, Node};
+
+/// Interval in which the state is gossiped to random peers.
+const GOSSIP_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Amount of random peers the state is gossiped to each interval.
+const GOSSIP_FANOUT: usize = 2;
+
+/// Amount of unacknowledged deltas after which a peer is send the full state instead.
+const FULL_STATE_AFTER: usize = 5;
+
+/// Client facing part of a replicated workload, for example Maelstrom's `g-set`.
+pub trait Workload {
+    type State: Crdt + Send + Sync + 'static;
+    type Payload: Debug + Serialize + DeserializeOwned + Send + Sync + 'static;
+
+    /// Handles a client request on the local replica, returning the reply and whether the state
+    /// changed.
+    fn handle(
+        state: &mut Self::State,
+        node_id: &str,
+        request: Self::Payload,
+    ) -> Result<(Self::Payload, bool)>;
+}
+
+/// Messages the nodes use to replicate their state.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum Replication<C> {
+    /// State of the sender, or the part of it the receiver is not known to have. `version`
+    /// increases every time the state of the sender changes.
+    Replicate {
+        state: C,
+        version: usize,
+    },
+    ReplicateOk {
+        version: usize,
+    },
+}
+
+/// Either a replication message between nodes, or a message of the workload.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum GossipPayload<C, P> {
+    Replication(Replication<C>),
+    Workload(P),
+}
+
+pub type CrdtPayload<W> = GossipPayload<<W as Workload>::State, <W as Workload>::Payload>;
+
+/// What is known to be replicated to a single peer.
+#[derive(Debug, Default)]
+struct Peer<C> {
+    /// Newest acknowledged version.
+    acked: usize,
+    /// State the peer is known to contain.
+    known: C,
+    /// Send deltas which are not yet acknowledged, by version.
+    sent: BTreeMap<usize, C>,
+}
+
+impl<C: Crdt> Peer<C> {
+    /// The state to send to the peer, only the delta since the last acknowledged state unless the
+    /// peer is too far behind.
+    fn next_delta(&mut self, state: &C, version: usize) -> C {
+        let delta = if self.sent.len() >= FULL_STATE_AFTER {
+            self.sent.clear();
+            state.clone()
+        } else {
+            state.delta(&self.known)
+        };
+
+        self.sent.insert(version, delta.clone());
+        delta
+    }
+
+    fn ack(&mut self, version: usize) {
+        self.acked = self.acked.max(version);
+
+        if let Some(delta) = self.sent.remove(&version) {
+            self.known.merge(&delta);
+            self.sent.retain(|sent, _| *sent > version);
+        }
+    }
+}
+
+/// Node replicating the state of a workload by gossiping deltas to random peers until they
+/// acknowledge the current version.
+pub struct CrdtNode<W: Workload> {
+    tx: mpsc::Sender<Message<CrdtPayload<W>>>,
+    rx: mpsc::Receiver<Message<CrdtPayload<W>>>,
+    node_id: String,
+    msg_id: usize,
+    nodes: Vec<String>,
+    state: W::State,
+    /// Version of the local state.
+    version: usize,
+    /// Newest version applied per origin.
+    applied: HashMap<String, usize>,
+    peers: HashMap<String, Peer<W::State>>,
+}
+
+impl<W> Node<CrdtPayload<W>> for CrdtNode<W>
+where
+    W: Workload,
+{
+    fn initialize(
+        tx: mpsc::Sender<Message<CrdtPayload<W>>>,
+        rx: mpsc::Receiver<Message<CrdtPayload<W>>>,
+        node_id: String,
+        other: Vec<String>,
+    ) -> Self {
+        Self {
+            tx,
+            rx,
+            node_id,
+            msg_id: 0,
+            nodes: other,
+            state: W::State::default(),
+            version: 0,
+            applied: HashMap::new(),
+            peers: HashMap::new(),
+        }
+    }
+
+    fn run(&mut self) -> Result<()> {
+        let mut backlog = VecDeque::<Message<CrdtPayload<W>>>::with_capacity(16);
+        let mut last_gossip = Instant::now();
+
+        loop {
+            if last_gossip.elapsed() >= GOSSIP_INTERVAL {
+                self.gossip()?;
+                last_gossip = Instant::now();
+            }
+
+            if let Some(msg) = backlog.pop_front() {
+                match msg.body.payload {
+                    GossipPayload::Replication(Replication::Replicate { state, version }) => {
+                        self.receive(&msg.src, &state, version);
+
+                        let payload =
+                            GossipPayload::Replication(Replication::ReplicateOk { version });
+                        self.send(payload, msg.src, msg.body.msg_id)?;
+                    }
+                    GossipPayload::Replication(Replication::ReplicateOk { version }) => {
+                        self.peers.entry(msg.src).or_default().ack(version);
+                    }
+                    GossipPayload::Workload(request) => {
+                        let (reply, changed) = W::handle(&mut self.state, &self.node_id, request)?;
+
+                        if changed {
+                            self.version += 1;
+                        }
+
+                        self.send(GossipPayload::Workload(reply), msg.src, msg.body.msg_id)?;
+                    }
+                }
+
+                continue;
+            }
+
+            match self.rx.recv_timeout(Duration::from_millis(50)) {
+                Ok(msg) => backlog.push_back(msg),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+        }
+    }
+}
+
+impl<W: Workload> CrdtNode<W> {
+    fn get_and_increment_id(&mut self) -> usize {
+        let old = self.msg_id;
+        self.msg_id += 1;
+        old
+    }
+
+    /// Merges replicated state of `src`, unless a newer version of it was already applied.
+    fn receive(&mut self, src: &str, state: &W::State, version: usize) {
+        let applied = self.applied.entry(src.to_string()).or_default();
+        if version <= *applied {
+            return;
+        }
+        *applied = version;
+
+        let delta = state.delta(&self.state);
+        if delta != W::State::default() {
+            self.state.merge(&delta);
+            self.version += 1;
+        }
+    }
+
+    /// Sends a few random peers which did not yet acknowledge the current version what they are
+    /// missing. Deltas are send until acknowledged, so lost or duplicated messages are repaired by
+    /// a later round.
+    fn gossip(&mut self) -> Result<()> {
+        let peers = self
+            .nodes
+            .iter()
+            .filter(|id| *id != &self.node_id)
+            .filter(|id| {
+                self.peers
+                    .get(*id)
+                    .map(|peer| peer.acked)
+                    .unwrap_or_default()
+                    < self.version
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for id in peers.choose_multiple(&mut rand::thread_rng(), GOSSIP_FANOUT) {
+            let peer = self.peers.entry(id.clone()).or_default();
+            let payload = GossipPayload::Replication(Replication::Replicate {
+                state: peer.next_delta(&self.state, self.version),
+                version: self.version,
+            });
+            self.send(payload, id.clone(), None)?;
+        }
+
+        Ok(())
+    }
+
+    fn send(
+        &mut self,
+        payload: CrdtPayload<W>,
+        dest: String,
+        in_reply_to: Option<usize>,
+    ) -> Result<()> {
+        let msg = Message {
+            src: self.node_id.clone(),
+            dest,
+            body: Body {
+                msg_id: Some(self.get_and_increment_id()),
+                in_reply_to,
+                payload,
+            },
+        };
+        self.tx.send(msg)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::bail;
+
+    use crate::crdt::GSet;
+
+    use super::*;
+
+    #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+    #[serde(rename_all = "snake_case", tag = "type")]
+    enum Payload {
+        Add { element: usize },
+        AddOk,
+        Read,
+        ReadOk { value: Vec<usize> },
+    }
+
+    struct GSetWorkload;
+
+    impl Workload for GSetWorkload {
+        type State = GSet<usize>;
+        type Payload = Payload;
+
+        fn handle(
+            state: &mut GSet<usize>,
+            _node_id: &str,
+            request: Payload,
+        ) -> Result<(Payload, bool)> {
+            match request {
+                Payload::Add { element } => {
+                    let changed = state.insert(element);
+                    Ok((Payload::AddOk, changed))
+                }
+                Payload::Read => Ok((
+                    Payload::ReadOk {
+                        value: state.iter().copied().collect(),
+                    },
+                    false,
+                )),
+                payload => bail!("Invalid message for node: {payload:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn payloads_deserialize_by_type() {
+        let replicate = serde_json::from_str::<CrdtPayload<GSetWorkload>>(
+            r#"{"type":"replicate","state":[1,2],"version":3}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            replicate,
+            GossipPayload::Replication(Replication::Replicate { version: 3, .. })
+        ));
+
+        let add =
+            serde_json::from_str::<CrdtPayload<GSetWorkload>>(r#"{"type":"add","element":1}"#)
+                .unwrap();
+        assert_eq!(add, GossipPayload::Workload(Payload::Add { element: 1 }));
+    }
+
+    #[test]
+    fn stale_replication_is_ignored() {
+        let (tx, _) = mpsc::channel();
+        let (_, rx) = mpsc::channel();
+        let nodes = vec!["n1".to_string(), "n2".to_string()];
+        let mut node = CrdtNode::<GSetWorkload>::initialize(tx, rx, "n1".to_string(), nodes);
+
+        let state = |elements: &[usize]| {
+            let mut state = GSet::default();
+            for element in elements {
+                state.insert(*element);
+            }
+            state
+        };
+
+        node.receive("n2", &state(&[5]), 2);
+        node.receive("n2", &state(&[3, 4]), 1);
+        assert_eq!(node.state, state(&[5]));
+        assert_eq!(node.version, 1);
+
+        // Nothing new, so the local version stays the same.
+        node.receive("n2", &state(&[5]), 3);
+        assert_eq!(node.version, 1);
+    }
+
+    #[test]
+    fn sends_delta_since_acknowledged_state() {
+        let mut peer = Peer::<GSet<usize>>::default();
+        let mut state = GSet::default();
+
+        state.insert(1);
+        assert_eq!(peer.next_delta(&state, 1), state);
+        peer.ack(1);
+
+        state.insert(2);
+        let delta = peer.next_delta(&state, 2);
+        assert_eq!(delta.iter().collect::<Vec<_>>(), vec![&2]);
+
+        state.insert(3);
+        let delta = peer.next_delta(&state, 3);
+        assert_eq!(delta.iter().collect::<Vec<_>>(), vec![&2, &3]);
+
+        peer.ack(3);
+        assert_eq!(peer.known, state);
+        assert!(peer.sent.is_empty());
+    }
+
+    #[test]
+    fn falls_back_to_full_state() {
+        let mut peer = Peer::<GSet<usize>>::default();
+        let mut state = GSet::default();
+
+        state.insert(0);
+        peer.next_delta(&state, 1);
+        peer.ack(1);
+
+        for version in 2..FULL_STATE_AFTER + 2 {
+            state.insert(version);
+            peer.next_delta(&state, version);
+        }
+
+        state.insert(100);
+        assert_eq!(peer.next_delta(&state, 100), state);
+        assert_eq!(peer.sent.len(), 1);
+
+        peer.ack(2);
+        assert_eq!(peer.known.len(), 1);
+    }
+}
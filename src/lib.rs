@@ -1,3 +1,8 @@
+pub mod crdt;
+pub mod gossip;
+pub mod raft;
+pub mod txn;
+
 use std::{
     io::{self, BufRead, StdoutLock, Write},
     sync::mpsc,
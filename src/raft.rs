@@ -0,0 +1,848 @@
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    fmt::Debug,
+    sync::mpsc::{self, RecvTimeoutError},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use rand::Rng;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{Body, Message, Node};
+
+/// Interval in which the leader sends entries, empty or not, to every follower.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Time without hearing from a leader after which a node starts an election, extended by a
+/// random amount up to the same duration so elections rarely collide.
+const ELECTION_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Maximum amount of entries send in a single append.
+const MAX_ENTRIES: usize = 64;
+
+/// Interval in which the node checks its timers when no messages arrive.
+const TICK: Duration = Duration::from_millis(10);
+
+/// Deterministic service replicated by applying the same requests in the same order on every
+/// node, for example Maelstrom's `lin-kv`.
+pub trait StateMachine: Default {
+    type Payload: Debug + Clone + Serialize + DeserializeOwned + Send + Sync + 'static;
+
+    /// Applies a committed client request, returning the reply.
+    fn apply(&mut self, request: Self::Payload) -> Self::Payload;
+
+    /// Reply to a request which can not be handled, because there is no known leader or its
+    /// entry was replaced by a new leader.
+    fn unavailable() -> Self::Payload;
+}
+
+/// Entry of the replicated log. Leaders start their term with an entry without a command, so
+/// entries of earlier terms are committed without waiting for a new request.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Entry<P> {
+    pub term: u64,
+    pub command: Option<P>,
+}
+
+/// Messages of the Raft protocol between nodes.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum RaftMessage<P> {
+    RequestVote {
+        term: u64,
+        last_log_index: usize,
+        last_log_term: u64,
+    },
+    RequestVoteOk {
+        term: u64,
+        vote_granted: bool,
+    },
+    AppendEntries {
+        term: u64,
+        prev_log_index: usize,
+        prev_log_term: u64,
+        entries: Vec<Entry<P>>,
+        leader_commit: usize,
+    },
+    /// `match_index` is the last entry known to match the leader, or on failure a hint from
+    /// where the leader should retry.
+    AppendEntriesOk {
+        term: u64,
+        success: bool,
+        match_index: usize,
+    },
+}
+
+impl<P> RaftMessage<P> {
+    fn term(&self) -> u64 {
+        match self {
+            RaftMessage::RequestVote { term, .. }
+            | RaftMessage::RequestVoteOk { term, .. }
+            | RaftMessage::AppendEntries { term, .. }
+            | RaftMessage::AppendEntriesOk { term, .. } => *term,
+        }
+    }
+}
+
+/// Either a Raft message between nodes, or a message of the replicated service.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum RaftPayload<P> {
+    Raft(RaftMessage<P>),
+    Workload(P),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// The Raft protocol of a single node, without any IO. Time is passed in, and the messages to
+/// send and the committed commands are taken out, so it can be driven by a node or a simulation.
+/// The log is only kept in memory.
+#[derive(Debug)]
+pub struct Raft<P> {
+    id: String,
+    peers: Vec<String>,
+    term: u64,
+    voted_for: Option<String>,
+    /// Entry `i` of the log is at index `i - 1`, index 0 is before the first entry.
+    log: Vec<Entry<P>>,
+    commit_index: usize,
+    last_applied: usize,
+    role: Role,
+    leader: Option<String>,
+    election_deadline: Instant,
+    last_heartbeat: Instant,
+    votes: BTreeSet<String>,
+    next_index: HashMap<String, usize>,
+    match_index: HashMap<String, usize>,
+    outbox: Vec<(String, RaftMessage<P>)>,
+}
+
+impl<P: Clone> Raft<P> {
+    /// Raft of node `id` in a cluster of `nodes`, which may include `id` itself.
+    pub fn new(id: String, nodes: Vec<String>, now: Instant) -> Self {
+        let peers = nodes.into_iter().filter(|node| *node != id).collect();
+
+        Self {
+            id,
+            peers,
+            term: 0,
+            voted_for: None,
+            log: Vec::new(),
+            commit_index: 0,
+            last_applied: 0,
+            role: Role::Follower,
+            leader: None,
+            election_deadline: now + election_timeout(),
+            last_heartbeat: now,
+            votes: BTreeSet::new(),
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            outbox: Vec::new(),
+        }
+    }
+
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.role == Role::Leader
+    }
+
+    /// The leader of the current term, if known.
+    pub fn leader(&self) -> Option<&str> {
+        self.leader.as_deref()
+    }
+
+    pub fn commit_index(&self) -> usize {
+        self.commit_index
+    }
+
+    /// Starts an election or sends heartbeats when due.
+    pub fn tick(&mut self, now: Instant) {
+        match self.role {
+            Role::Leader => {
+                if now.duration_since(self.last_heartbeat) >= HEARTBEAT_INTERVAL {
+                    self.broadcast_entries(now);
+                }
+            }
+            Role::Follower | Role::Candidate => {
+                if now >= self.election_deadline {
+                    self.start_election(now);
+                }
+            }
+        }
+    }
+
+    /// Appends a command to the log when this node is the leader, returning its index and term.
+    /// The command is applied once that entry is returned by [`Raft::take_committed`].
+    pub fn propose(&mut self, command: P) -> Option<(usize, u64)> {
+        if !self.is_leader() {
+            return None;
+        }
+
+        self.log.push(Entry {
+            term: self.term,
+            command: Some(command),
+        });
+        let index = self.last_index();
+
+        // Followers which are behind already have entries in flight, and get this one with the
+        // next acknowledgement.
+        for peer in self.peers.clone() {
+            if self.next_index[&peer] == index {
+                self.send_entries(&peer);
+            }
+        }
+        self.advance_commit();
+
+        Some((index, self.term))
+    }
+
+    pub fn handle(&mut self, src: &str, msg: RaftMessage<P>, now: Instant) {
+        if msg.term() > self.term {
+            self.become_follower(msg.term());
+        }
+
+        match msg {
+            RaftMessage::RequestVote {
+                term,
+                last_log_index,
+                last_log_term,
+            } => {
+                let up_to_date =
+                    (last_log_term, last_log_index) >= (self.last_term(), self.last_index());
+                let vote_granted = term == self.term
+                    && up_to_date
+                    && self.voted_for.as_deref().is_none_or(|voted| voted == src);
+
+                if vote_granted {
+                    self.voted_for = Some(src.to_string());
+                    self.election_deadline = now + election_timeout();
+                }
+
+                let reply = RaftMessage::RequestVoteOk {
+                    term: self.term,
+                    vote_granted,
+                };
+                self.outbox.push((src.to_string(), reply));
+            }
+            RaftMessage::RequestVoteOk { term, vote_granted } => {
+                if self.role == Role::Candidate
+                    && term == self.term
+                    && vote_granted
+                    && self.peers.iter().any(|peer| peer == src)
+                {
+                    self.votes.insert(src.to_string());
+                    self.check_votes(now);
+                }
+            }
+            RaftMessage::AppendEntries {
+                term,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+            } => {
+                let (success, match_index) = if term < self.term {
+                    (false, 0)
+                } else {
+                    self.role = Role::Follower;
+                    self.leader = Some(src.to_string());
+                    self.election_deadline = now + election_timeout();
+
+                    self.append_entries(prev_log_index, prev_log_term, entries, leader_commit)
+                };
+
+                let reply = RaftMessage::AppendEntriesOk {
+                    term: self.term,
+                    success,
+                    match_index,
+                };
+                self.outbox.push((src.to_string(), reply));
+            }
+            RaftMessage::AppendEntriesOk {
+                term,
+                success,
+                match_index,
+            } => {
+                if self.role != Role::Leader || term != self.term {
+                    return;
+                }
+
+                // Replies of nodes outside the cluster are ignored.
+                let (Some(&next_index), Some(&matched)) =
+                    (self.next_index.get(src), self.match_index.get(src))
+                else {
+                    return;
+                };
+
+                if success {
+                    let matched = matched.max(match_index);
+                    self.match_index.insert(src.to_string(), matched);
+                    self.next_index.insert(src.to_string(), matched + 1);
+                    self.advance_commit();
+
+                    if matched < self.last_index() {
+                        self.send_entries(src);
+                    }
+                } else {
+                    let retry = (match_index + 1).min(next_index - 1).max(1);
+                    self.next_index.insert(src.to_string(), retry);
+                    self.send_entries(src);
+                }
+            }
+        }
+    }
+
+    /// Messages to send, with their destination.
+    pub fn take_outbox(&mut self) -> Vec<(String, RaftMessage<P>)> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    /// Commands committed since the last call, with the index and term of their entry.
+    pub fn take_committed(&mut self) -> Vec<(usize, u64, P)> {
+        let mut committed = Vec::new();
+
+        while self.last_applied < self.commit_index {
+            self.last_applied += 1;
+
+            let entry = &self.log[self.last_applied - 1];
+            if let Some(command) = &entry.command {
+                committed.push((self.last_applied, entry.term, command.clone()));
+            }
+        }
+
+        committed
+    }
+
+    fn last_index(&self) -> usize {
+        self.log.len()
+    }
+
+    fn last_term(&self) -> u64 {
+        self.log.last().map(|entry| entry.term).unwrap_or_default()
+    }
+
+    fn term_at(&self, index: usize) -> Option<u64> {
+        match index {
+            0 => Some(0),
+            index => self.log.get(index - 1).map(|entry| entry.term),
+        }
+    }
+
+    fn majority(&self) -> usize {
+        let nodes = self.peers.len() + 1;
+        nodes / 2 + 1
+    }
+
+    fn become_follower(&mut self, term: u64) {
+        self.term = term;
+        self.role = Role::Follower;
+        self.voted_for = None;
+        self.leader = None;
+        self.votes.clear();
+    }
+
+    fn start_election(&mut self, now: Instant) {
+        self.term += 1;
+        self.role = Role::Candidate;
+        self.voted_for = Some(self.id.clone());
+        self.leader = None;
+        self.votes = BTreeSet::from([self.id.clone()]);
+        self.election_deadline = now + election_timeout();
+
+        for peer in &self.peers {
+            let request = RaftMessage::RequestVote {
+                term: self.term,
+                last_log_index: self.last_index(),
+                last_log_term: self.last_term(),
+            };
+            self.outbox.push((peer.clone(), request));
+        }
+
+        self.check_votes(now);
+    }
+
+    fn check_votes(&mut self, now: Instant) {
+        if self.votes.len() < self.majority() {
+            return;
+        }
+
+        self.role = Role::Leader;
+        self.leader = Some(self.id.clone());
+        self.log.push(Entry {
+            term: self.term,
+            command: None,
+        });
+
+        for peer in &self.peers {
+            self.next_index.insert(peer.clone(), self.last_index());
+            self.match_index.insert(peer.clone(), 0);
+        }
+
+        self.broadcast_entries(now);
+        self.advance_commit();
+    }
+
+    fn broadcast_entries(&mut self, now: Instant) {
+        self.last_heartbeat = now;
+
+        for peer in self.peers.clone() {
+            self.send_entries(&peer);
+        }
+    }
+
+    fn send_entries(&mut self, peer: &str) {
+        let prev_log_index = self.next_index[peer] - 1;
+        let entries = self.log[prev_log_index..]
+            .iter()
+            .take(MAX_ENTRIES)
+            .cloned()
+            .collect();
+
+        let request = RaftMessage::AppendEntries {
+            term: self.term,
+            prev_log_index,
+            prev_log_term: self.term_at(prev_log_index).unwrap_or_default(),
+            entries,
+            leader_commit: self.commit_index,
+        };
+        self.outbox.push((peer.to_string(), request));
+    }
+
+    /// Appends the entries of the leader after the previous entry, replacing conflicting ones,
+    /// returning whether the previous entry matched and the index matching the leader up to.
+    fn append_entries(
+        &mut self,
+        prev_log_index: usize,
+        prev_log_term: u64,
+        entries: Vec<Entry<P>>,
+        leader_commit: usize,
+    ) -> (bool, usize) {
+        if self.term_at(prev_log_index) != Some(prev_log_term) {
+            return (
+                false,
+                self.last_index().min(prev_log_index.saturating_sub(1)),
+            );
+        }
+
+        let matched = prev_log_index + entries.len();
+        for (index, entry) in (prev_log_index + 1..).zip(entries) {
+            match self.term_at(index) {
+                Some(term) if term == entry.term => {}
+                Some(_) => {
+                    self.log.truncate(index - 1);
+                    self.log.push(entry);
+                }
+                None => self.log.push(entry),
+            }
+        }
+
+        self.commit_index = self.commit_index.max(leader_commit.min(matched));
+        (true, matched)
+    }
+
+    /// Commits the newest entry of the current term stored on a majority, and everything before.
+    fn advance_commit(&mut self) {
+        for index in (self.commit_index + 1..=self.last_index()).rev() {
+            if self.term_at(index) != Some(self.term) {
+                break;
+            }
+
+            let replicas = 1 + self
+                .match_index
+                .values()
+                .filter(|matched| **matched >= index)
+                .count();
+            if replicas >= self.majority() {
+                self.commit_index = index;
+                break;
+            }
+        }
+    }
+}
+
+fn election_timeout() -> Duration {
+    ELECTION_TIMEOUT + rand::thread_rng().gen_range(Duration::ZERO..ELECTION_TIMEOUT)
+}
+
+/// Sender of a request, to reply to once it is handled.
+#[derive(Debug, Clone)]
+struct Client {
+    src: String,
+    msg_id: Option<usize>,
+}
+
+/// Node serving a state machine replicated with Raft. Requests are applied once committed by the
+/// leader, other nodes forward them to the leader they know of.
+pub struct RaftNode<M: StateMachine> {
+    tx: mpsc::Sender<Message<RaftPayload<M::Payload>>>,
+    rx: mpsc::Receiver<Message<RaftPayload<M::Payload>>>,
+    node_id: String,
+    msg_id: usize,
+    raft: Raft<M::Payload>,
+    machine: M,
+    /// Clients waiting for their request to be applied, by log index with the term it was
+    /// proposed in.
+    waiting: HashMap<usize, (u64, Client)>,
+    /// Clients of requests forwarded to the leader, by the id of the forwarded message.
+    forwarded: HashMap<usize, Client>,
+}
+
+impl<M> Node<RaftPayload<M::Payload>> for RaftNode<M>
+where
+    M: StateMachine,
+{
+    fn initialize(
+        tx: mpsc::Sender<Message<RaftPayload<M::Payload>>>,
+        rx: mpsc::Receiver<Message<RaftPayload<M::Payload>>>,
+        node_id: String,
+        other: Vec<String>,
+    ) -> Self {
+        let raft = Raft::new(node_id.clone(), other, Instant::now());
+
+        Self {
+            tx,
+            rx,
+            node_id,
+            msg_id: 0,
+            raft,
+            machine: M::default(),
+            waiting: HashMap::with_capacity(16),
+            forwarded: HashMap::with_capacity(16),
+        }
+    }
+
+    fn run(&mut self) -> Result<()> {
+        let mut backlog = VecDeque::<Message<RaftPayload<M::Payload>>>::with_capacity(16);
+
+        loop {
+            self.raft.tick(Instant::now());
+
+            if let Some(msg) = backlog.pop_front() {
+                self.handle(msg)?;
+            }
+
+            self.flush()?;
+
+            if !backlog.is_empty() {
+                continue;
+            }
+
+            match self.rx.recv_timeout(TICK) {
+                Ok(msg) => backlog.push_back(msg),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+        }
+    }
+}
+
+impl<M: StateMachine> RaftNode<M> {
+    fn get_and_increment_id(&mut self) -> usize {
+        let old = self.msg_id;
+        self.msg_id += 1;
+        old
+    }
+
+    fn handle(&mut self, msg: Message<RaftPayload<M::Payload>>) -> Result<()> {
+        let client = Client {
+            src: msg.src,
+            msg_id: msg.body.msg_id,
+        };
+
+        match msg.body.payload {
+            RaftPayload::Raft(raft_msg) => self.raft.handle(&client.src, raft_msg, Instant::now()),
+            RaftPayload::Workload(reply) if msg.body.in_reply_to.is_some() => {
+                // Reply of the leader to a forwarded request.
+                if let Some(client) = msg
+                    .body
+                    .in_reply_to
+                    .and_then(|msg_id| self.forwarded.remove(&msg_id))
+                {
+                    self.send(RaftPayload::Workload(reply), client.src, client.msg_id)?;
+                }
+            }
+            RaftPayload::Workload(request) => {
+                if let Some((index, term)) = self.raft.propose(request.clone()) {
+                    // A client waiting at the same index had its entry replaced by this one, after
+                    // this node lost and regained leadership, so it will never be applied.
+                    if let Some((_, replaced)) = self.waiting.insert(index, (term, client)) {
+                        let reply = RaftPayload::Workload(M::unavailable());
+                        self.send(reply, replaced.src, replaced.msg_id)?;
+                    }
+                    return Ok(());
+                }
+
+                match self.raft.leader().map(str::to_string) {
+                    Some(leader) if leader != self.node_id => {
+                        let msg_id = self.send(RaftPayload::Workload(request), leader, None)?;
+                        self.forwarded.insert(msg_id, client);
+                    }
+                    _ => {
+                        let reply = RaftPayload::Workload(M::unavailable());
+                        self.send(reply, client.src, client.msg_id)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sends the messages of the protocol, and applies and answers the committed requests.
+    fn flush(&mut self) -> Result<()> {
+        for (dest, raft_msg) in self.raft.take_outbox() {
+            self.send(RaftPayload::Raft(raft_msg), dest, None)?;
+        }
+
+        for (index, term, request) in self.raft.take_committed() {
+            let reply = self.machine.apply(request);
+
+            if let Some((proposed, client)) = self.waiting.remove(&index) {
+                // A different entry at the index means the request was dropped by a new leader.
+                let reply = if proposed == term {
+                    reply
+                } else {
+                    M::unavailable()
+                };
+                self.send(RaftPayload::Workload(reply), client.src, client.msg_id)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn send(
+        &mut self,
+        payload: RaftPayload<M::Payload>,
+        dest: String,
+        in_reply_to: Option<usize>,
+    ) -> Result<usize> {
+        let msg_id = self.get_and_increment_id();
+        let msg = Message {
+            src: self.node_id.clone(),
+            dest,
+            body: Body {
+                msg_id: Some(msg_id),
+                in_reply_to,
+                payload,
+            },
+        };
+        self.tx.send(msg)?;
+
+        Ok(msg_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    /// Simulated cluster with a network which can be partitioned, advancing time in fixed steps.
+    struct Simulation {
+        now: Instant,
+        nodes: BTreeMap<String, Raft<usize>>,
+        /// Messages in flight, delivered in the next step.
+        network: Vec<(String, String, RaftMessage<usize>)>,
+        /// Links over which messages are dropped.
+        cut: BTreeSet<(String, String)>,
+        applied: BTreeMap<String, Vec<usize>>,
+    }
+
+    impl Simulation {
+        fn new(size: usize) -> Self {
+            let now = Instant::now();
+            let ids = (1..=size).map(|i| format!("n{i}")).collect::<Vec<_>>();
+            let nodes = ids
+                .iter()
+                .map(|id| (id.clone(), Raft::new(id.clone(), ids.clone(), now)))
+                .collect();
+
+            Self {
+                now,
+                nodes,
+                network: Vec::new(),
+                cut: BTreeSet::new(),
+                applied: BTreeMap::new(),
+            }
+        }
+
+        fn step(&mut self) {
+            self.now += Duration::from_millis(10);
+
+            for (src, dest, msg) in std::mem::take(&mut self.network) {
+                self.nodes
+                    .get_mut(&dest)
+                    .unwrap()
+                    .handle(&src, msg, self.now);
+            }
+
+            for (id, node) in &mut self.nodes {
+                node.tick(self.now);
+
+                for (dest, msg) in node.take_outbox() {
+                    if !self.cut.contains(&(id.clone(), dest.clone())) {
+                        self.network.push((id.clone(), dest, msg));
+                    }
+                }
+
+                let applied = self.applied.entry(id.clone()).or_default();
+                applied.extend(node.take_committed().into_iter().map(|(_, _, c)| c));
+            }
+        }
+
+        fn run_for(&mut self, duration: Duration) {
+            for _ in 0..duration.as_millis() / 10 {
+                self.step();
+            }
+        }
+
+        /// The leader with the highest term among `among`.
+        fn leader(&self, among: &[&str]) -> String {
+            among
+                .iter()
+                .map(|id| &self.nodes[*id])
+                .filter(|node| node.is_leader())
+                .max_by_key(|node| node.term())
+                .map(|node| node.id.clone())
+                .expect("No leader elected")
+        }
+
+        fn propose(&mut self, node: &str, command: usize) -> Option<(usize, u64)> {
+            self.nodes.get_mut(node).unwrap().propose(command)
+        }
+
+        /// Drops all messages between the groups.
+        fn partition(&mut self, groups: &[&[&str]]) {
+            for (i, group) in groups.iter().enumerate() {
+                for other in &groups[i + 1..] {
+                    for a in group.iter() {
+                        for b in other.iter() {
+                            self.cut.insert((a.to_string(), b.to_string()));
+                            self.cut.insert((b.to_string(), a.to_string()));
+                        }
+                    }
+                }
+            }
+        }
+
+        fn heal(&mut self) {
+            self.cut.clear();
+        }
+    }
+
+    const ALL: [&str; 5] = ["n1", "n2", "n3", "n4", "n5"];
+
+    #[test]
+    fn payloads_deserialize_by_type() {
+        let vote = serde_json::from_str::<RaftPayload<usize>>(
+            r#"{"type":"request_vote","term":2,"last_log_index":1,"last_log_term":1}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            vote,
+            RaftPayload::Raft(RaftMessage::RequestVote { term: 2, .. })
+        ));
+
+        let request = serde_json::from_str::<RaftPayload<usize>>("5").unwrap();
+        assert_eq!(request, RaftPayload::Workload(5));
+    }
+
+    #[test]
+    fn elects_single_leader_and_replicates() {
+        let mut sim = Simulation::new(5);
+        sim.run_for(Duration::from_secs(2));
+
+        let leader = sim.leader(&ALL);
+        let leaders = sim
+            .nodes
+            .values()
+            .filter(|node| node.is_leader() && node.term() == sim.nodes[&leader].term())
+            .count();
+        assert_eq!(leaders, 1);
+
+        for command in 1..=3 {
+            assert!(sim.propose(&leader, command).is_some());
+        }
+        let follower = ALL.iter().find(|id| **id != leader).unwrap();
+        assert_eq!(sim.propose(follower, 4), None);
+
+        sim.run_for(Duration::from_secs(1));
+        for id in ALL {
+            assert_eq!(sim.applied[id], vec![1, 2, 3], "{id}");
+        }
+    }
+
+    #[test]
+    fn ignores_nodes_outside_cluster() {
+        let mut sim = Simulation::new(3);
+        sim.run_for(Duration::from_secs(2));
+
+        let leader = sim.leader(&["n1", "n2", "n3"]);
+        let node = sim.nodes.get_mut(&leader).unwrap();
+        let term = node.term();
+        let reply = RaftMessage::AppendEntriesOk {
+            term,
+            success: true,
+            match_index: 1,
+        };
+        node.handle("n9", reply, sim.now);
+
+        assert!(node.is_leader());
+        assert!(node.take_outbox().iter().all(|(dest, _)| dest != "n9"));
+    }
+
+    #[test]
+    fn majority_progresses_during_partition() {
+        let mut sim = Simulation::new(5);
+        sim.run_for(Duration::from_secs(2));
+
+        let old_leader = sim.leader(&ALL);
+        sim.propose(&old_leader, 1).unwrap();
+        sim.run_for(Duration::from_secs(1));
+
+        let others = ALL
+            .iter()
+            .copied()
+            .filter(|id| *id != old_leader)
+            .collect::<Vec<_>>();
+        let minority = [old_leader.as_str(), others[0]];
+        let majority = &others[1..];
+        sim.partition(&[&minority, majority]);
+
+        // Can not be committed without a majority.
+        sim.propose(&old_leader, 2).unwrap();
+        sim.run_for(Duration::from_secs(3));
+
+        let new_leader = sim.leader(majority);
+        assert!(sim.nodes[&new_leader].term() > sim.nodes[&old_leader].term());
+        sim.propose(&new_leader, 3).unwrap();
+        sim.run_for(Duration::from_secs(1));
+
+        for id in majority {
+            assert_eq!(sim.applied[*id], vec![1, 3], "{id}");
+        }
+        for id in minority {
+            assert_eq!(sim.applied[id], vec![1], "{id}");
+        }
+
+        sim.heal();
+        sim.run_for(Duration::from_secs(3));
+
+        let log = &sim.nodes[&new_leader].log;
+        for id in ALL {
+            assert_eq!(sim.applied[id], vec![1, 3], "{id}");
+            assert_eq!(&sim.nodes[id].log, log, "{id}");
+        }
+    }
+}
@@ -0,0 +1,485 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OpKind {
+    #[serde(rename = "r")]
+    Read,
+    #[serde(rename = "w")]
+    Write,
+}
+
+/// A micro-operation of a transaction on a register, encoded as `["r", k, v]` or `["w", k, v]`.
+/// The value of a read is `None` until the transaction is executed, and when the register was
+/// never written.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Op(pub OpKind, pub usize, pub Option<usize>);
+
+/// Outcome of a transaction as observed by the client.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Committed,
+    Aborted,
+    /// The client does not know whether the transaction committed, for example after a timeout.
+    /// It is checked as if it committed.
+    Indeterminate,
+}
+
+/// A transaction of a recorded history, with the values of its reads.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Txn {
+    pub status: Status,
+    pub ops: Vec<Op>,
+}
+
+/// Isolation levels as defined by Adya, from weak to strong.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Isolation {
+    /// PL-1, proscribes G0.
+    ReadUncommitted,
+    /// PL-2, proscribes G0 and G1.
+    ReadCommitted,
+    /// PL-3, proscribes G0, G1 and G2.
+    Serializable,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum AnomalyKind {
+    /// Write cycle, a cycle of write dependencies.
+    G0,
+    /// Aborted read, a committed transaction read a value written by an aborted one.
+    G1a,
+    /// Intermediate read, a committed transaction read a value which was overwritten by the
+    /// transaction writing it.
+    G1b,
+    /// Circular information flow, a cycle of write and read dependencies.
+    G1c,
+    /// A cycle of dependencies with at least one anti-dependency.
+    G2,
+}
+
+impl AnomalyKind {
+    /// The strongest isolation level which allows this anomaly.
+    fn allowed_by(&self) -> Option<Isolation> {
+        match self {
+            AnomalyKind::G0 => None,
+            AnomalyKind::G1a | AnomalyKind::G1b | AnomalyKind::G1c => {
+                Some(Isolation::ReadUncommitted)
+            }
+            AnomalyKind::G2 => Some(Isolation::ReadCommitted),
+        }
+    }
+}
+
+/// An anomaly with the indexes of the transactions involved, in the history passed to [`check`].
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Anomaly {
+    pub kind: AnomalyKind,
+    pub txns: Vec<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Report {
+    pub anomalies: Vec<Anomaly>,
+    /// Strongest isolation level the history satisfies, `None` when it does not even satisfy read
+    /// uncommitted.
+    pub level: Option<Isolation>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Dependency {
+    /// The target overwrote a value of the source.
+    Ww,
+    /// The target read a value of the source.
+    Wr,
+    /// The target overwrote a value the source read.
+    Rw,
+}
+
+/// Checks a history of register transactions for the anomalies described by Adya, assuming every
+/// value is written at most once per key.
+///
+/// The order in which values of a key were installed is not recorded, so it is inferred: the
+/// initial empty value precedes every value, and a value a transaction read precedes the value the
+/// same transaction wrote afterwards, making the writer of the read value precede it. A blind
+/// write, one without reading the key first, is therefore only ordered after the initial value
+/// and before the writes of transactions which read it, cycles which depend on any other order of
+/// blind writes go undetected.
+pub fn check(history: &[Txn]) -> Report {
+    let mut anomalies = Vec::new();
+
+    // Writer of every value with whether it is the final write of that transaction to the key.
+    let mut writers = HashMap::<(usize, usize), (usize, bool)>::new();
+    // Transactions writing and reading each key, ignoring aborted ones.
+    let mut key_writers = HashMap::<usize, BTreeSet<usize>>::new();
+    let mut readers = HashMap::<(usize, Option<usize>), BTreeSet<usize>>::new();
+    for (id, txn) in history.iter().enumerate() {
+        let finals = final_writes(txn);
+        for Op(kind, key, value) in &txn.ops {
+            if let (OpKind::Write, Some(value)) = (kind, value) {
+                writers.insert((*key, *value), (id, finals.get(key) == Some(value)));
+            }
+        }
+
+        if txn.status != Status::Aborted {
+            for key in finals.keys() {
+                key_writers.entry(*key).or_default().insert(id);
+            }
+            for (key, read) in external_reads(txn) {
+                readers.entry((key, read)).or_default().insert(id);
+            }
+        }
+    }
+
+    let mut graph = vec![Vec::new(); history.len()];
+    for (id, txn) in history.iter().enumerate() {
+        if txn.status == Status::Aborted {
+            continue;
+        }
+
+        let finals = final_writes(txn);
+        for (key, read) in external_reads(txn) {
+            let Some(value) = read else {
+                // The initial value precedes every written value.
+                for writer in key_writers.get(&key).into_iter().flatten() {
+                    if *writer != id {
+                        graph[id].push((*writer, Dependency::Rw));
+                    }
+                }
+                continue;
+            };
+
+            let Some((writer, is_final)) = writers.get(&(key, value)).copied() else {
+                continue;
+            };
+            if writer == id {
+                continue;
+            }
+
+            if history[writer].status == Status::Aborted {
+                anomalies.push(Anomaly {
+                    kind: AnomalyKind::G1a,
+                    txns: vec![writer, id],
+                });
+                continue;
+            }
+
+            if !is_final {
+                anomalies.push(Anomaly {
+                    kind: AnomalyKind::G1b,
+                    txns: vec![writer, id],
+                });
+            }
+            graph[writer].push((id, Dependency::Wr));
+
+            // The value written after reading is the next version of the key.
+            if finals.contains_key(&key) {
+                graph[writer].push((id, Dependency::Ww));
+                for reader in &readers[&(key, read)] {
+                    if *reader != id {
+                        graph[*reader].push((id, Dependency::Rw));
+                    }
+                }
+            }
+        }
+    }
+
+    let cycles = [
+        (AnomalyKind::G0, &[Dependency::Ww][..], Dependency::Ww),
+        (
+            AnomalyKind::G1c,
+            &[Dependency::Ww, Dependency::Wr][..],
+            Dependency::Wr,
+        ),
+        (
+            AnomalyKind::G2,
+            &[Dependency::Ww, Dependency::Wr, Dependency::Rw][..],
+            Dependency::Rw,
+        ),
+    ];
+    for (kind, dependencies, required) in cycles {
+        for component in components(&graph, dependencies) {
+            let members = component.iter().copied().collect::<BTreeSet<_>>();
+            let has_required = component.iter().any(|source| {
+                graph[*source]
+                    .iter()
+                    .any(|(target, dependency)| *dependency == required && members.contains(target))
+            });
+
+            if has_required {
+                anomalies.push(Anomaly {
+                    kind,
+                    txns: members.into_iter().collect(),
+                });
+            }
+        }
+    }
+
+    let level = anomalies
+        .iter()
+        .map(|anomaly| anomaly.kind.allowed_by())
+        .min()
+        .unwrap_or(Some(Isolation::Serializable));
+
+    Report { anomalies, level }
+}
+
+/// The last value a transaction wrote to each key.
+fn final_writes(txn: &Txn) -> BTreeMap<usize, usize> {
+    txn.ops
+        .iter()
+        .filter_map(|Op(kind, key, value)| match kind {
+            OpKind::Write => Some((*key, (*value)?)),
+            OpKind::Read => None,
+        })
+        .collect()
+}
+
+/// The value a transaction read from each key before writing it, the version it observed of
+/// other transactions.
+fn external_reads(txn: &Txn) -> BTreeMap<usize, Option<usize>> {
+    let mut reads = BTreeMap::new();
+    let mut written = BTreeSet::new();
+
+    for Op(kind, key, value) in &txn.ops {
+        match kind {
+            OpKind::Read if !written.contains(key) => {
+                reads.entry(*key).or_insert(*value);
+            }
+            OpKind::Read => {}
+            OpKind::Write => {
+                written.insert(*key);
+            }
+        }
+    }
+
+    reads
+}
+
+/// Strongly connected components with more than one transaction, following only the given
+/// dependencies, using Tarjan's algorithm with an explicit stack so long histories can not overflow
+/// the call stack.
+fn components(graph: &[Vec<(usize, Dependency)>], dependencies: &[Dependency]) -> Vec<Vec<usize>> {
+    let mut index = 0;
+    let mut indexes = vec![None; graph.len()];
+    let mut low = vec![0; graph.len()];
+    let mut stack = Vec::new();
+    let mut on_stack = vec![false; graph.len()];
+    let mut components = Vec::new();
+
+    for root in 0..graph.len() {
+        if indexes[root].is_some() {
+            continue;
+        }
+
+        // Nodes being visited, with the position of the next dependency to follow.
+        let mut visiting = vec![(root, 0)];
+        indexes[root] = Some(index);
+        low[root] = index;
+        index += 1;
+        stack.push(root);
+        on_stack[root] = true;
+
+        while let Some((node, next)) = visiting.last_mut() {
+            let node = *node;
+
+            if let Some((target, dependency)) = graph[node].get(*next) {
+                *next += 1;
+
+                if !dependencies.contains(dependency) {
+                    continue;
+                }
+
+                match indexes[*target] {
+                    None => {
+                        indexes[*target] = Some(index);
+                        low[*target] = index;
+                        index += 1;
+                        stack.push(*target);
+                        on_stack[*target] = true;
+                        visiting.push((*target, 0));
+                    }
+                    Some(target_index) if on_stack[*target] => {
+                        low[node] = low[node].min(target_index);
+                    }
+                    Some(_) => {}
+                }
+                continue;
+            }
+
+            visiting.pop();
+            if let Some((parent, _)) = visiting.last() {
+                low[*parent] = low[*parent].min(low[node]);
+            }
+
+            if Some(low[node]) == indexes[node] {
+                let mut component = Vec::new();
+                while let Some(member) = stack.pop() {
+                    on_stack[member] = false;
+                    component.push(member);
+                    if member == node {
+                        break;
+                    }
+                }
+
+                if component.len() > 1 {
+                    components.push(component);
+                }
+            }
+        }
+    }
+
+    components
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn txn(ops: serde_json::Value) -> Txn {
+        Txn {
+            status: Status::Committed,
+            ops: serde_json::from_value(ops).unwrap(),
+        }
+    }
+
+    fn kinds(report: &Report) -> Vec<AnomalyKind> {
+        report
+            .anomalies
+            .iter()
+            .map(|anomaly| anomaly.kind)
+            .collect()
+    }
+
+    #[test]
+    fn serial_history_is_serializable() {
+        let history = [
+            txn(serde_json::json!([["r", 1, null], ["w", 1, 1]])),
+            txn(serde_json::json!([["r", 1, 1], ["w", 1, 2], ["w", 2, 3]])),
+            txn(serde_json::json!([["r", 1, 2], ["r", 2, 3]])),
+        ];
+
+        let report = check(&history);
+        assert!(report.anomalies.is_empty(), "{report:?}");
+        assert_eq!(report.level, Some(Isolation::Serializable));
+    }
+
+    #[test]
+    fn long_chains_do_not_overflow() {
+        let history = (0..100_000)
+            .map(|value| txn(serde_json::json!([["r", 1, value], ["w", 1, value + 1]])))
+            .collect::<Vec<_>>();
+
+        assert!(check(&history).anomalies.is_empty());
+    }
+
+    #[test]
+    fn detects_aborted_and_intermediate_reads() {
+        let mut aborted = txn(serde_json::json!([["w", 1, 1]]));
+        aborted.status = Status::Aborted;
+        let history = [
+            aborted,
+            txn(serde_json::json!([["w", 2, 2], ["w", 2, 3]])),
+            txn(serde_json::json!([["r", 1, 1], ["r", 2, 2]])),
+        ];
+
+        let report = check(&history);
+        assert_eq!(
+            report.anomalies,
+            vec![
+                Anomaly {
+                    kind: AnomalyKind::G1a,
+                    txns: vec![0, 2]
+                },
+                Anomaly {
+                    kind: AnomalyKind::G1b,
+                    txns: vec![1, 2]
+                },
+            ]
+        );
+        assert_eq!(report.level, Some(Isolation::ReadUncommitted));
+    }
+
+    #[test]
+    fn detects_write_cycle() {
+        let history = [
+            txn(serde_json::json!([["r", 1, 2], ["w", 1, 1], ["w", 2, 1]])),
+            txn(serde_json::json!([["r", 2, 1], ["w", 2, 2], ["w", 1, 2]])),
+        ];
+
+        let report = check(&history);
+        assert!(kinds(&report).contains(&AnomalyKind::G0));
+        assert_eq!(report.anomalies[0].txns, vec![0, 1]);
+        assert_eq!(report.level, None);
+    }
+
+    #[test]
+    fn orders_blind_writes_through_reads() {
+        // Both first writes are blind, but each is read by the other transaction before it
+        // overwrites the key.
+        let history = [
+            txn(serde_json::json!([["w", 1, 1], ["r", 2, 2], ["w", 2, 3]])),
+            txn(serde_json::json!([["w", 2, 2], ["r", 1, 1], ["w", 1, 4]])),
+        ];
+
+        let report = check(&history);
+        assert!(kinds(&report).contains(&AnomalyKind::G0));
+        assert_eq!(report.level, None);
+    }
+
+    #[test]
+    fn misses_cycles_of_unordered_blind_writes() {
+        // The final reads show the writes were installed in opposite orders on both keys, but
+        // without a read before a write the order of the blind writes is unknown.
+        let history = [
+            txn(serde_json::json!([["w", 1, 1], ["w", 2, 1]])),
+            txn(serde_json::json!([["w", 1, 2], ["w", 2, 2]])),
+            txn(serde_json::json!([["r", 1, 1], ["r", 2, 2]])),
+        ];
+
+        let report = check(&history);
+        assert!(report.anomalies.is_empty(), "{report:?}");
+        assert_eq!(report.level, Some(Isolation::Serializable));
+    }
+
+    #[test]
+    fn detects_circular_information_flow() {
+        let history = [
+            txn(serde_json::json!([["w", 1, 1], ["r", 2, 2]])),
+            txn(serde_json::json!([["w", 2, 2], ["r", 1, 1]])),
+        ];
+
+        let report = check(&history);
+        assert_eq!(kinds(&report), vec![AnomalyKind::G1c]);
+        assert_eq!(report.level, Some(Isolation::ReadUncommitted));
+    }
+
+    #[test]
+    fn detects_write_skew() {
+        let history = [
+            txn(serde_json::json!([
+                ["r", 1, null],
+                ["r", 2, null],
+                ["w", 1, 1]
+            ])),
+            txn(serde_json::json!([
+                ["r", 1, null],
+                ["r", 2, null],
+                ["w", 2, 2]
+            ])),
+        ];
+
+        let report = check(&history);
+        assert_eq!(
+            report.anomalies,
+            vec![Anomaly {
+                kind: AnomalyKind::G2,
+                txns: vec![0, 1]
+            }]
+        );
+        assert_eq!(report.level, Some(Isolation::ReadCommitted));
+    }
+}
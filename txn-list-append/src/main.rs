@@ -0,0 +1,661 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::mpsc,
+};
+
+use anyhow::{bail, Result};
+use dist_sys::{Body, Message, Node};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+const LIN_KV: &str = "lin-kv";
+
+/// Key in `lin-kv` of the root, the map from every list key to the thunk holding its value.
+const ROOT_KEY: &str = "root";
+
+/// Error code of `lin-kv` for a missing key.
+const KEY_DOES_NOT_EXIST: usize = 20;
+
+/// Thunk id per list key. Thunks are written once and never change, so a transaction commits by
+/// swapping the root to one pointing at new thunks.
+type Root = BTreeMap<usize, String>;
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+enum OpKind {
+    #[serde(rename = "r")]
+    Read,
+    #[serde(rename = "append")]
+    Append,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+#[serde(untagged)]
+enum Element {
+    One(usize),
+    List(Vec<usize>),
+}
+
+/// A micro-operation of a transaction, encoded as `["append", k, v]` or `["r", k, null]`. The
+/// list of a read is filled in when the transaction is executed.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+struct Op(OpKind, usize, Option<Element>);
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+#[serde(rename_all = "snake_case", tag = "type")]
+enum Payload {
+    Txn {
+        txn: Vec<Op>,
+    },
+    TxnOk {
+        txn: Vec<Op>,
+    },
+    Read {
+        key: String,
+    },
+    ReadOk {
+        value: Value,
+    },
+    Write {
+        key: String,
+        value: Value,
+    },
+    WriteOk,
+    Cas {
+        key: String,
+        from: Value,
+        to: Value,
+        create_if_not_exists: bool,
+    },
+    CasOk,
+    Error {
+        code: usize,
+        text: String,
+    },
+}
+
+/// Sender of a request, to reply to once it is handled.
+#[derive(Debug, Clone)]
+struct Client {
+    src: String,
+    msg_id: Option<usize>,
+}
+
+/// A transaction being executed, restarted from reading the root when another transaction
+/// committed in between.
+#[derive(Debug)]
+struct Attempt {
+    client: Client,
+    txn: Vec<Op>,
+    /// Root the transaction reads from.
+    root: Root,
+    /// Amount of thunks still being read or written.
+    outstanding: usize,
+    /// The executed transaction and the root it commits, once executed.
+    commit: Option<(Vec<Op>, Root)>,
+}
+
+/// Request to `lin-kv` on behalf of an attempt.
+#[derive(Debug)]
+enum Pending {
+    /// Creating an empty root, which every node does when there is none yet so concurrent
+    /// creations can not overwrite a committed transaction.
+    CreateRoot {
+        attempt: usize,
+    },
+    ReadRoot {
+        attempt: usize,
+    },
+    ReadThunk {
+        attempt: usize,
+        thunk: String,
+    },
+    WriteThunk {
+        attempt: usize,
+    },
+    /// Swapping the root, which fails when another transaction committed since it was read.
+    CasRoot {
+        attempt: usize,
+    },
+}
+
+impl Pending {
+    fn attempt(&self) -> usize {
+        match self {
+            Pending::CreateRoot { attempt }
+            | Pending::ReadRoot { attempt }
+            | Pending::ReadThunk { attempt, .. }
+            | Pending::WriteThunk { attempt }
+            | Pending::CasRoot { attempt } => *attempt,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ListAppendNode {
+    tx: mpsc::Sender<Message<Payload>>,
+    rx: mpsc::Receiver<Message<Payload>>,
+    node_id: String,
+    msg_id: usize,
+    attempts: HashMap<usize, Attempt>,
+    pending: HashMap<usize, Pending>,
+    /// Thunks read or written by this node, which never change once written.
+    thunks: HashMap<String, Vec<usize>>,
+}
+
+impl Node<Payload> for ListAppendNode {
+    fn initialize(
+        tx: mpsc::Sender<Message<Payload>>,
+        rx: mpsc::Receiver<Message<Payload>>,
+        node_id: String,
+        _other: Vec<String>,
+    ) -> Self {
+        Self {
+            tx,
+            rx,
+            node_id,
+            msg_id: 0,
+            attempts: HashMap::with_capacity(16),
+            pending: HashMap::with_capacity(16),
+            thunks: HashMap::new(),
+        }
+    }
+
+    fn run(&mut self) -> Result<()> {
+        while let Ok(msg) = self.rx.recv() {
+            self.handle(msg)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl ListAppendNode {
+    fn handle(&mut self, next: Message<Payload>) -> Result<()> {
+        match next.body.payload {
+            Payload::Txn { txn } => {
+                let attempt = self.get_and_increment_id();
+                self.attempts.insert(
+                    attempt,
+                    Attempt {
+                        client: Client {
+                            src: next.src,
+                            msg_id: next.body.msg_id,
+                        },
+                        txn,
+                        root: Root::new(),
+                        outstanding: 0,
+                        commit: None,
+                    },
+                );
+
+                self.read_root(attempt)?;
+            }
+            Payload::TxnOk { .. } => bail!("Invalid message for node"),
+            payload => {
+                // Replies to requests of an attempt which restarted since are ignored.
+                let Some(pending) = next
+                    .body
+                    .in_reply_to
+                    .and_then(|msg_id| self.pending.remove(&msg_id))
+                else {
+                    return Ok(());
+                };
+
+                self.handle_kv_response(payload, pending)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle_kv_response(&mut self, payload: Payload, pending: Pending) -> Result<()> {
+        match (payload, pending) {
+            (Payload::ReadOk { value }, Pending::ReadRoot { attempt }) => {
+                self.fetch_thunks(attempt, serde_json::from_value(value)?)?;
+            }
+            (Payload::Error { code, .. }, Pending::ReadRoot { attempt })
+                if code == KEY_DOES_NOT_EXIST =>
+            {
+                let empty = serde_json::to_value(Root::new())?;
+                self.send_kv(
+                    Payload::Cas {
+                        key: ROOT_KEY.to_string(),
+                        from: empty.clone(),
+                        to: empty,
+                        create_if_not_exists: true,
+                    },
+                    Pending::CreateRoot { attempt },
+                )?;
+            }
+            (Payload::ReadOk { value }, Pending::ReadThunk { attempt, thunk }) => {
+                self.thunks.insert(thunk, serde_json::from_value(value)?);
+                self.finish_thunk(attempt)?;
+            }
+            (Payload::WriteOk, Pending::WriteThunk { attempt }) => {
+                self.finish_thunk(attempt)?;
+            }
+            (Payload::CasOk, Pending::CasRoot { attempt }) => {
+                if let Some(Attempt {
+                    client,
+                    commit: Some((txn, _)),
+                    ..
+                }) = self.attempts.remove(&attempt)
+                {
+                    self.reply(&client, Payload::TxnOk { txn })?;
+                }
+            }
+            // The root was created, by this node or another, or changed since it was read, or
+            // `lin-kv` failed, all of which are retried from a fresh root.
+            (_, pending) => self.read_root(pending.attempt())?,
+        }
+
+        Ok(())
+    }
+
+    /// (Re)starts an attempt by reading the current root, dropping anything still in flight for it.
+    fn read_root(&mut self, attempt: usize) -> Result<()> {
+        self.pending
+            .retain(|_, pending| pending.attempt() != attempt);
+
+        self.send_kv(
+            Payload::Read {
+                key: ROOT_KEY.to_string(),
+            },
+            Pending::ReadRoot { attempt },
+        )
+    }
+
+    /// Reads the thunks of all keys of the transaction which are not cached yet.
+    fn fetch_thunks(&mut self, id: usize, root: Root) -> Result<()> {
+        let Some(attempt) = self.attempts.get_mut(&id) else {
+            return Ok(());
+        };
+
+        let missing = attempt
+            .txn
+            .iter()
+            .filter_map(|Op(_, key, _)| root.get(key))
+            .filter(|thunk| !self.thunks.contains_key(*thunk))
+            .cloned()
+            .collect::<BTreeSet<_>>();
+
+        attempt.root = root;
+        attempt.outstanding = missing.len();
+        attempt.commit = None;
+
+        if missing.is_empty() {
+            return self.execute(id);
+        }
+
+        for thunk in missing {
+            self.send_kv(
+                Payload::Read { key: thunk.clone() },
+                Pending::ReadThunk { attempt: id, thunk },
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn finish_thunk(&mut self, id: usize) -> Result<()> {
+        let Some(attempt) = self.attempts.get_mut(&id) else {
+            return Ok(());
+        };
+
+        attempt.outstanding -= 1;
+        if attempt.outstanding > 0 {
+            return Ok(());
+        }
+
+        match &attempt.commit {
+            None => self.execute(id),
+            Some((_, root)) => {
+                let cas = Payload::Cas {
+                    key: ROOT_KEY.to_string(),
+                    from: serde_json::to_value(&attempt.root)?,
+                    to: serde_json::to_value(root)?,
+                    create_if_not_exists: false,
+                };
+                self.send_kv(cas, Pending::CasRoot { attempt: id })
+            }
+        }
+    }
+
+    /// Executes the transaction against the lists of its root. A read-only transaction is done,
+    /// otherwise the changed lists are written to new thunks before swapping the root.
+    fn execute(&mut self, id: usize) -> Result<()> {
+        let Some(attempt) = self.attempts.get(&id) else {
+            return Ok(());
+        };
+
+        let mut lists = BTreeMap::<usize, Vec<usize>>::new();
+        let mut appended = BTreeSet::new();
+        let mut txn = Vec::with_capacity(attempt.txn.len());
+
+        for Op(kind, key, value) in &attempt.txn {
+            let list = lists.entry(*key).or_insert_with(|| {
+                attempt
+                    .root
+                    .get(key)
+                    .and_then(|thunk| self.thunks.get(thunk))
+                    .cloned()
+                    .unwrap_or_default()
+            });
+
+            match (kind, value) {
+                (OpKind::Read, _) => txn.push(Op(*kind, *key, Some(Element::List(list.clone())))),
+                (OpKind::Append, Some(Element::One(element))) => {
+                    list.push(*element);
+                    appended.insert(*key);
+                    txn.push(Op(*kind, *key, value.clone()));
+                }
+                (OpKind::Append, _) => bail!("Invalid append of {value:?}"),
+            }
+        }
+
+        let client = attempt.client.clone();
+        let mut root = attempt.root.clone();
+
+        if appended.is_empty() {
+            self.attempts.remove(&id);
+            return self.reply(&client, Payload::TxnOk { txn });
+        }
+
+        for key in &appended {
+            let thunk_id = self.get_and_increment_id();
+            let thunk = format!("{}-{thunk_id}", self.node_id);
+            let list = lists.remove(key).unwrap_or_default();
+
+            self.send_kv(
+                Payload::Write {
+                    key: thunk.clone(),
+                    value: serde_json::to_value(&list)?,
+                },
+                Pending::WriteThunk { attempt: id },
+            )?;
+            self.thunks.insert(thunk.clone(), list);
+            root.insert(*key, thunk);
+        }
+
+        if let Some(attempt) = self.attempts.get_mut(&id) {
+            attempt.outstanding = appended.len();
+            attempt.commit = Some((txn, root));
+        }
+
+        Ok(())
+    }
+
+    fn get_and_increment_id(&mut self) -> usize {
+        let old = self.msg_id;
+        self.msg_id += 1;
+        old
+    }
+
+    fn generate_message(
+        &mut self,
+        payload: Payload,
+        dest: String,
+        in_reply_to: Option<usize>,
+    ) -> Message<Payload> {
+        Message {
+            src: self.node_id.clone(),
+            dest,
+            body: Body {
+                msg_id: Some(self.get_and_increment_id()),
+                in_reply_to,
+                payload,
+            },
+        }
+    }
+
+    fn send_kv(&mut self, payload: Payload, pending: Pending) -> Result<()> {
+        let msg = self.generate_message(payload, LIN_KV.to_string(), None);
+        let msg_id = msg.body.msg_id.expect("Generated messages have an id");
+
+        self.pending.insert(msg_id, pending);
+        self.tx.send(msg)?;
+
+        Ok(())
+    }
+
+    fn reply(&mut self, client: &Client, payload: Payload) -> Result<()> {
+        let ack_msg = self.generate_message(payload, client.src.clone(), client.msg_id);
+        self.tx.send(ack_msg)?;
+
+        Ok(())
+    }
+}
+
+fn main() -> Result<()> {
+    dist_sys::run_dist_sys::<ListAppendNode, Payload>()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use super::*;
+
+    /// In-memory stand-in for `lin-kv`.
+    type Kv = HashMap<String, Value>;
+
+    fn spawn() -> (
+        mpsc::Sender<Message<Payload>>,
+        mpsc::Receiver<Message<Payload>>,
+    ) {
+        let (in_tx, in_rx) = mpsc::channel::<Message<Payload>>();
+        let (out_tx, out_rx) = mpsc::channel::<Message<Payload>>();
+
+        thread::spawn(move || {
+            let mut node = ListAppendNode::initialize(out_tx, in_rx, "n1".to_string(), vec![]);
+            node.run().unwrap();
+        });
+
+        (in_tx, out_rx)
+    }
+
+    fn serve(kv: &mut Kv, payload: Payload) -> Payload {
+        let missing = Payload::Error {
+            code: KEY_DOES_NOT_EXIST,
+            text: "not found".to_string(),
+        };
+
+        match payload {
+            Payload::Read { key } => match kv.get(&key) {
+                Some(value) => Payload::ReadOk {
+                    value: value.clone(),
+                },
+                None => missing,
+            },
+            Payload::Write { key, value } => {
+                kv.insert(key, value);
+                Payload::WriteOk
+            }
+            Payload::Cas {
+                key,
+                from,
+                to,
+                create_if_not_exists,
+            } => match kv.get(&key) {
+                Some(current) if *current == from => {
+                    kv.insert(key, to);
+                    Payload::CasOk
+                }
+                Some(_) => Payload::Error {
+                    code: 22,
+                    text: "precondition failed".to_string(),
+                },
+                None if create_if_not_exists => {
+                    kv.insert(key, to);
+                    Payload::CasOk
+                }
+                None => missing,
+            },
+            payload => panic!("Unexpected request for lin-kv: {payload:?}"),
+        }
+    }
+
+    /// Runs a transaction, serving the requests of the node to `lin-kv` from `kv` after passing
+    /// them to `interfere`, and returns the reply.
+    fn run_txn(
+        (in_tx, out_rx): &(
+            mpsc::Sender<Message<Payload>>,
+            mpsc::Receiver<Message<Payload>>,
+        ),
+        kv: &mut Kv,
+        ops: Value,
+        mut interfere: impl FnMut(&mut Kv, &Payload),
+    ) -> Value {
+        in_tx
+            .send(Message {
+                src: "c1".to_string(),
+                dest: "n1".to_string(),
+                body: Body {
+                    msg_id: Some(1),
+                    in_reply_to: None,
+                    payload: Payload::Txn {
+                        txn: serde_json::from_value(ops).unwrap(),
+                    },
+                },
+            })
+            .unwrap();
+
+        loop {
+            let msg = out_rx
+                .recv_timeout(Duration::from_millis(500))
+                .expect("Failed to get a response in a reasonable time");
+
+            if msg.dest != LIN_KV {
+                assert_eq!(msg.body.in_reply_to, Some(1));
+                return serde_json::to_value(msg.body.payload).unwrap();
+            }
+
+            interfere(kv, &msg.body.payload);
+            let payload = serve(kv, msg.body.payload);
+            in_tx
+                .send(Message {
+                    src: LIN_KV.to_string(),
+                    dest: "n1".to_string(),
+                    body: Body {
+                        msg_id: None,
+                        in_reply_to: msg.body.msg_id,
+                        payload,
+                    },
+                })
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn appends_and_reads_lists() {
+        let node = spawn();
+        let mut kv = Kv::new();
+
+        let response = run_txn(
+            &node,
+            &mut kv,
+            serde_json::json!([["append", 1, 3], ["r", 1, null]]),
+            |_, _| {},
+        );
+        assert_eq!(
+            response,
+            serde_json::json!({
+                "type": "txn_ok",
+                "txn": [["append", 1, 3], ["r", 1, [3]]]
+            })
+        );
+
+        let response = run_txn(
+            &node,
+            &mut kv,
+            serde_json::json!([["r", 1, null], ["append", 1, 4], ["r", 2, null]]),
+            |_, _| {},
+        );
+        assert_eq!(
+            response,
+            serde_json::json!({
+                "type": "txn_ok",
+                "txn": [["r", 1, [3]], ["append", 1, 4], ["r", 2, []]]
+            })
+        );
+    }
+
+    #[test]
+    fn ignores_replies_of_restarted_attempt() {
+        let mut kv = Kv::new();
+
+        run_txn(
+            &spawn(),
+            &mut kv,
+            serde_json::json!([["append", 1, 3], ["append", 2, 4]]),
+            |_, _| {},
+        );
+
+        // The first thunk read fails, restarting the attempt while the second one is in flight.
+        let mut failed = false;
+        let mut removed = None;
+        let response = run_txn(
+            &spawn(),
+            &mut kv,
+            serde_json::json!([["r", 1, null], ["r", 2, null]]),
+            |kv, payload| {
+                if let Some((key, value)) = removed.take() {
+                    kv.insert(key, value);
+                }
+
+                match payload {
+                    Payload::Read { key } if key != ROOT_KEY && !failed => {
+                        removed = kv.remove_entry(key);
+                        failed = true;
+                    }
+                    _ => {}
+                }
+            },
+        );
+
+        assert_eq!(
+            response,
+            serde_json::json!({
+                "type": "txn_ok",
+                "txn": [["r", 1, [3]], ["r", 2, [4]]]
+            })
+        );
+    }
+
+    #[test]
+    fn retries_when_root_changed() {
+        let node = spawn();
+        let mut kv = Kv::new();
+
+        run_txn(
+            &node,
+            &mut kv,
+            serde_json::json!([["append", 1, 3]]),
+            |_, _| {},
+        );
+
+        // Another node commits an append to the same list while this one is committing.
+        let mut interfered = false;
+        let response = run_txn(
+            &node,
+            &mut kv,
+            serde_json::json!([["append", 1, 5], ["r", 1, null]]),
+            |kv, payload| {
+                if matches!(payload, Payload::Cas { .. }) && !interfered {
+                    kv.insert("n2-0".to_string(), serde_json::json!([3, 4]));
+                    kv.insert(ROOT_KEY.to_string(), serde_json::json!({"1": "n2-0"}));
+                    interfered = true;
+                }
+            },
+        );
+
+        assert_eq!(
+            response,
+            serde_json::json!({
+                "type": "txn_ok",
+                "txn": [["append", 1, 5], ["r", 1, [3, 4, 5]]]
+            })
+        );
+    }
+}
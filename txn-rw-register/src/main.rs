@@ -0,0 +1,399 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::mpsc::{self, RecvTimeoutError},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Result};
+use dist_sys::{
+    crdt::Timestamp,
+    txn::{Op, OpKind},
+    Body, Message, Node,
+};
+use serde::{Deserialize, Serialize};
+
+/// Interval after which an unacknowledged replicated transaction is resent.
+const REPLICATE_RETRY: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+#[serde(rename_all = "snake_case", tag = "type")]
+enum Payload {
+    Txn {
+        txn: Vec<Op>,
+    },
+    TxnOk {
+        txn: Vec<Op>,
+    },
+    /// The final writes of a committed transaction, applied by peers as a whole so no
+    /// intermediate or partial state of a transaction becomes visible.
+    Replicate {
+        writes: BTreeMap<usize, usize>,
+        timestamp: Timestamp,
+    },
+    ReplicateOk,
+}
+
+#[derive(Debug)]
+struct TxnNode {
+    tx: mpsc::Sender<Message<Payload>>,
+    rx: mpsc::Receiver<Message<Payload>>,
+    node_id: String,
+    msg_id: usize,
+    nodes: Vec<String>,
+    /// Value of every register, with the timestamp of the transaction which wrote it. The write
+    /// of the transaction with the highest timestamp wins.
+    registers: HashMap<usize, (Timestamp, usize)>,
+    /// Highest timestamp seen, transactions of this node are ordered after it.
+    clock: u64,
+    unacked: HashMap<usize, (Instant, Message<Payload>)>,
+}
+
+impl Node<Payload> for TxnNode {
+    fn initialize(
+        tx: mpsc::Sender<Message<Payload>>,
+        rx: mpsc::Receiver<Message<Payload>>,
+        node_id: String,
+        other: Vec<String>,
+    ) -> Self {
+        Self {
+            tx,
+            rx,
+            node_id,
+            msg_id: 0,
+            nodes: other,
+            registers: HashMap::new(),
+            clock: 0,
+            unacked: HashMap::with_capacity(16),
+        }
+    }
+
+    fn run(&mut self) -> Result<()> {
+        let mut queue = VecDeque::<Message<Payload>>::with_capacity(16);
+
+        loop {
+            self.retry_replication()?;
+
+            if let Some(next) = queue.pop_front() {
+                self.handle(next)?;
+                continue;
+            }
+
+            match self.rx.recv_timeout(Duration::from_millis(50)) {
+                Ok(next) => queue.push_back(next),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl TxnNode {
+    fn handle(&mut self, next: Message<Payload>) -> Result<()> {
+        match next.body.payload {
+            Payload::Txn { ref txn } => {
+                let (txn, writes) = self.execute(txn);
+
+                if !writes.is_empty() {
+                    let timestamp = self.next_timestamp();
+                    self.commit(&writes, &timestamp);
+                    self.send_to_peers(Payload::Replicate { writes, timestamp })?;
+                }
+
+                self.send_response(&next, Payload::TxnOk { txn })?;
+            }
+            Payload::Replicate {
+                ref writes,
+                ref timestamp,
+            } => {
+                self.clock = self.clock.max(timestamp.time);
+                self.commit(writes, timestamp);
+                self.send_response(&next, Payload::ReplicateOk)?;
+            }
+            Payload::ReplicateOk => {
+                if let Some(msg_id) = next.body.in_reply_to {
+                    self.unacked.remove(&msg_id);
+                }
+            }
+            Payload::TxnOk { .. } => bail!("Invalid message for node"),
+        }
+
+        Ok(())
+    }
+
+    /// Executes `txn` against the registers, returning the operations with read values filled
+    /// in and the final value written to every key. Reads observe the transaction's own writes,
+    /// but nothing is visible to other transactions until the writes are committed.
+    fn execute(&self, txn: &[Op]) -> (Vec<Op>, BTreeMap<usize, usize>) {
+        let mut writes = BTreeMap::new();
+
+        let txn = txn
+            .iter()
+            .map(|Op(kind, key, value)| match kind {
+                OpKind::Read => {
+                    let value = writes
+                        .get(key)
+                        .or_else(|| self.registers.get(key).map(|(_, value)| value));
+                    Op(*kind, *key, value.copied())
+                }
+                OpKind::Write => {
+                    if let Some(value) = value {
+                        writes.insert(*key, *value);
+                    }
+                    Op(*kind, *key, *value)
+                }
+            })
+            .collect();
+
+        (txn, writes)
+    }
+
+    /// Applies the writes of a transaction to every register not already written by a newer
+    /// transaction.
+    fn commit(&mut self, writes: &BTreeMap<usize, usize>, timestamp: &Timestamp) {
+        for (key, value) in writes {
+            match self.registers.get(key) {
+                Some((current, _)) if current >= timestamp => {}
+                _ => {
+                    self.registers.insert(*key, (timestamp.clone(), *value));
+                }
+            }
+        }
+    }
+
+    /// Timestamp for a new transaction, the wall clock or just after the newest timestamp seen
+    /// when the clock lags behind.
+    fn next_timestamp(&mut self) -> Timestamp {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis() as u64)
+            .unwrap_or_default();
+        self.clock = now.max(self.clock + 1);
+
+        Timestamp {
+            time: self.clock,
+            replica: self.node_id.clone(),
+        }
+    }
+
+    /// Sends a message to all other nodes, retrying until acknowledged.
+    fn send_to_peers(&mut self, payload: Payload) -> Result<()> {
+        for node in self.nodes.clone() {
+            if node == self.node_id {
+                continue;
+            }
+
+            let replicate_msg = self.generate_message(payload.clone(), node, None);
+            self.tx.send(replicate_msg.clone())?;
+
+            let msg_id = replicate_msg
+                .body
+                .msg_id
+                .expect("Generated messages have an id");
+            self.unacked.insert(msg_id, (Instant::now(), replicate_msg));
+        }
+
+        Ok(())
+    }
+
+    fn retry_replication(&mut self) -> Result<()> {
+        for (send_at, msg) in self.unacked.values_mut() {
+            if send_at.elapsed() >= REPLICATE_RETRY {
+                self.tx.send(msg.clone())?;
+                *send_at = Instant::now();
+            }
+        }
+
+        Ok(())
+    }
+
+    fn get_and_increment_id(&mut self) -> usize {
+        let old = self.msg_id;
+        self.msg_id += 1;
+        old
+    }
+
+    fn generate_message(
+        &mut self,
+        payload: Payload,
+        dest: String,
+        in_reply_to: Option<usize>,
+    ) -> Message<Payload> {
+        Message {
+            src: self.node_id.clone(),
+            dest,
+            body: Body {
+                msg_id: Some(self.get_and_increment_id()),
+                in_reply_to,
+                payload,
+            },
+        }
+    }
+
+    fn send_response(&mut self, msg: &Message<Payload>, payload: Payload) -> Result<()> {
+        let ack_msg = self.generate_message(payload, msg.src.clone(), msg.body.msg_id);
+        self.tx.send(ack_msg)?;
+
+        Ok(())
+    }
+}
+
+fn main() -> Result<()> {
+    dist_sys::run_dist_sys::<TxnNode, Payload>()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::thread;
+
+    use super::*;
+
+    fn spawn(
+        nodes: Vec<String>,
+    ) -> (
+        mpsc::Sender<Message<Payload>>,
+        mpsc::Receiver<Message<Payload>>,
+    ) {
+        let (in_tx, in_rx) = mpsc::channel::<Message<Payload>>();
+        let (out_tx, out_rx) = mpsc::channel::<Message<Payload>>();
+
+        thread::spawn(move || {
+            let mut node = TxnNode::initialize(out_tx, in_rx, "n1".to_string(), nodes);
+            node.run().unwrap();
+        });
+
+        (in_tx, out_rx)
+    }
+
+    fn message(src: &str, msg_id: usize, payload: Payload) -> Message<Payload> {
+        Message {
+            src: src.to_string(),
+            dest: "n1".to_string(),
+            body: Body {
+                msg_id: Some(msg_id),
+                in_reply_to: None,
+                payload,
+            },
+        }
+    }
+
+    fn txn(ops: serde_json::Value) -> Payload {
+        Payload::Txn {
+            txn: serde_json::from_value(ops).unwrap(),
+        }
+    }
+
+    fn recv(out_rx: &mpsc::Receiver<Message<Payload>>) -> Message<Payload> {
+        out_rx
+            .recv_timeout(Duration::from_millis(500))
+            .expect("Failed to get a response in a reasonable time")
+    }
+
+    #[test]
+    fn reads_own_and_earlier_writes() {
+        let (in_tx, out_rx) = spawn(vec![]);
+
+        let txns: [serde_json::Value; 2] = [
+            serde_json::json!([["r", 1, null], ["w", 1, 6], ["r", 1, null]]),
+            serde_json::json!([["r", 1, null], ["r", 2, null]]),
+        ];
+
+        let mut responses = Vec::new();
+        for (msg_id, ops) in txns.into_iter().enumerate() {
+            in_tx.send(message("c1", msg_id, txn(ops))).unwrap();
+
+            let response = recv(&out_rx);
+            assert_eq!(response.body.in_reply_to, Some(msg_id));
+            responses.push(serde_json::to_value(response.body.payload).unwrap());
+        }
+
+        assert_eq!(
+            responses,
+            vec![
+                serde_json::json!({
+                    "type": "txn_ok",
+                    "txn": [["r", 1, null], ["w", 1, 6], ["r", 1, 6]]
+                }),
+                serde_json::json!({
+                    "type": "txn_ok",
+                    "txn": [["r", 1, 6], ["r", 2, null]]
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn replicates_final_writes_of_transaction() {
+        let (in_tx, out_rx) = spawn(vec!["n1".to_string(), "n2".to_string()]);
+
+        in_tx
+            .send(message(
+                "c1",
+                1,
+                txn(serde_json::json!([["w", 1, 6], ["w", 1, 7], ["w", 2, 8]])),
+            ))
+            .unwrap();
+
+        let replicate = recv(&out_rx);
+        assert_eq!(replicate.dest, "n2");
+        let Payload::Replicate { writes, timestamp } = replicate.body.payload else {
+            panic!("Expected replicate, got {:?}", replicate.body.payload);
+        };
+        assert_eq!(writes, BTreeMap::from([(1, 7), (2, 8)]));
+        assert_eq!(timestamp.replica, "n1");
+
+        assert!(matches!(recv(&out_rx).body.payload, Payload::TxnOk { .. }));
+
+        // Only one of the writes of the older transaction loses against the newer one.
+        let older = Timestamp {
+            time: timestamp.time - 1,
+            replica: "n2".to_string(),
+        };
+        in_tx
+            .send(message(
+                "n2",
+                2,
+                Payload::Replicate {
+                    writes: BTreeMap::from([(1, 9), (3, 10)]),
+                    timestamp: older,
+                },
+            ))
+            .unwrap();
+        assert_eq!(recv(&out_rx).body.payload, Payload::ReplicateOk);
+
+        in_tx
+            .send(message(
+                "c1",
+                3,
+                txn(serde_json::json!([["r", 1, null], ["r", 3, null]])),
+            ))
+            .unwrap();
+        assert_eq!(
+            serde_json::to_value(recv(&out_rx).body.payload).unwrap(),
+            serde_json::json!({
+                "type": "txn_ok",
+                "txn": [["r", 1, 7], ["r", 3, 10]]
+            })
+        );
+    }
+
+    #[test]
+    fn retries_unacknowledged_replication() {
+        let (in_tx, out_rx) = spawn(vec!["n1".to_string(), "n2".to_string()]);
+
+        in_tx
+            .send(message("c1", 1, txn(serde_json::json!([["w", 1, 6]]))))
+            .unwrap();
+
+        let replicate = recv(&out_rx);
+        assert!(matches!(recv(&out_rx).body.payload, Payload::TxnOk { .. }));
+
+        let retry = out_rx
+            .recv_timeout(REPLICATE_RETRY * 2)
+            .expect("Replication should be retried");
+        assert_eq!(retry, replicate);
+    }
+}